@@ -0,0 +1,246 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fs,
+    hash::Hash,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::storefront::StorefrontInfo;
+
+/// Settings for keeping backend lookups on disk, so they can be reused by later runs.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DiskCacheConfig {
+    pub dir: PathBuf,
+    /// How long a cached answer stays valid, in seconds.
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+}
+
+fn default_ttl() -> u64 {
+    3600
+}
+
+/// Caches the backend's answers to storefront info and is-free-software lookups. Answers are kept in memory for the
+/// rest of the run and, if a `DiskCacheConfig` is given, on disk for `ttl` seconds. The hit and miss counts are logged
+/// when the cache is dropped, i.e. at the end of the run.
+pub struct BackendCache {
+    disk: Option<DiskCacheConfig>,
+    storefront_info: RefCell<HashMap<String, StorefrontInfo>>,
+    is_free_software: RefCell<HashMap<(String, Option<String>), bool>>,
+    hits: Cell<u32>,
+    misses: Cell<u32>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct DiskEntry<V> {
+    /// When the answer was fetched, in seconds since the Unix epoch.
+    fetched_at: u64,
+    value: V,
+}
+
+impl BackendCache {
+    pub fn new(disk: Option<DiskCacheConfig>) -> Self {
+        Self {
+            disk,
+            storefront_info: RefCell::new(HashMap::new()),
+            is_free_software: RefCell::new(HashMap::new()),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    pub fn storefront_info(
+        &self,
+        app_id: &str,
+        fetch: impl FnOnce() -> Result<StorefrontInfo>,
+    ) -> Result<StorefrontInfo> {
+        self.get(
+            &self.storefront_info,
+            app_id.to_string(),
+            Path::new("storefront-info").join(format!("{app_id}.json")),
+            fetch,
+        )
+    }
+
+    pub fn is_free_software(
+        &self,
+        app_id: &str,
+        license: Option<&str>,
+        fetch: impl FnOnce() -> Result<bool>,
+    ) -> Result<bool> {
+        let filename = match license {
+            Some(license) => format!("license-{}.json", escape_filename(license)),
+            None => "no-license.json".to_string(),
+        };
+
+        self.get(
+            &self.is_free_software,
+            (app_id.to_string(), license.map(str::to_string)),
+            Path::new("is-free-software").join(app_id).join(filename),
+            fetch,
+        )
+    }
+
+    /// Looks up a value in memory, then on disk, and only then fetches it. `path` is the cache file, relative to the
+    /// cache directory.
+    fn get<K: Eq + Hash, V: Clone + Serialize + DeserializeOwned>(
+        &self,
+        memory: &RefCell<HashMap<K, V>>,
+        key: K,
+        path: PathBuf,
+        fetch: impl FnOnce() -> Result<V>,
+    ) -> Result<V> {
+        if let Some(value) = memory.borrow().get(&key) {
+            self.hits.set(self.hits.get() + 1);
+            return Ok(value.clone());
+        }
+
+        let path = self.disk.as_ref().map(|disk| disk.dir.join(path));
+
+        let value = match path.as_deref().and_then(|path| self.read_disk(path)) {
+            Some(value) => {
+                self.hits.set(self.hits.get() + 1);
+                value
+            }
+            None => {
+                self.misses.set(self.misses.get() + 1);
+                let value = fetch()?;
+                if let Some(path) = &path {
+                    /* The cache is only an optimization, so don't fail the run if it can't be written */
+                    if let Err(e) = write_disk(path, &value) {
+                        warn!("Failed to write cache file {}: {}", path.display(), e);
+                    }
+                }
+                value
+            }
+        };
+
+        memory.borrow_mut().insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// Reads a cache file. Returns `None` if it doesn't exist, can't be read, or is older than the TTL.
+    fn read_disk<V: DeserializeOwned>(&self, path: &Path) -> Option<V> {
+        let ttl = self.disk.as_ref()?.ttl;
+        let entry: DiskEntry<V> = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+        (now().saturating_sub(entry.fetched_at) < ttl).then_some(entry.value)
+    }
+}
+
+impl Default for BackendCache {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Drop for BackendCache {
+    fn drop(&mut self) {
+        if self.hits.get() + self.misses.get() > 0 {
+            info!(
+                "Backend cache: {} hits, {} misses",
+                self.hits.get(),
+                self.misses.get()
+            );
+        }
+    }
+}
+
+fn write_disk<V: Serialize>(path: &Path, value: &V) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    /* Write to a temporary file and rename it, so concurrent runs never see a partially written file */
+    let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(
+        &tmp_path,
+        serde_json::to_string(&DiskEntry {
+            fetched_at: now(),
+            value,
+        })?,
+    )?;
+    fs::rename(tmp_path, path)?;
+
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Makes a string (such as an SPDX expression) safe to use in a filename by percent-encoding everything except
+/// letters, digits, `.`, `-` and `+`.
+fn escape_filename(s: &str) -> String {
+    s.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-.+".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_filename() {
+        assert_eq!(escape_filename("GPL-3.0-or-later"), "GPL-3.0-or-later");
+        assert_eq!(
+            escape_filename("MIT OR LicenseRef-proprietary=https://example.com"),
+            "MIT%20OR%20LicenseRef-proprietary%3Dhttps%3A%2F%2Fexample.com"
+        );
+    }
+
+    #[test]
+    fn test_backend_cache() {
+        let dir = std::env::temp_dir().join(format!("flathub-hooks-cache-{}", std::process::id()));
+        let disk = Some(DiskCacheConfig {
+            dir: dir.clone(),
+            ttl: 60,
+        });
+
+        let cached = || -> Result<bool> { panic!("should be cached") };
+
+        let cache = BackendCache::new(disk.clone());
+        assert!(cache
+            .is_free_software("org.flatpak.Test", Some("MIT"), || Ok(true))
+            .unwrap());
+        assert!(cache
+            .is_free_software("org.flatpak.Test", Some("MIT"), cached)
+            .unwrap());
+        assert!(!cache
+            .is_free_software("org.flatpak.Test", None, || Ok(false))
+            .unwrap());
+        assert_eq!((cache.hits.get(), cache.misses.get()), (1, 2));
+
+        /* A new run gets the answers from disk */
+        let cache = BackendCache::new(disk);
+        assert!(cache
+            .is_free_software("org.flatpak.Test", Some("MIT"), cached)
+            .unwrap());
+
+        /* Unless they've expired */
+        let cache = BackendCache::new(Some(DiskCacheConfig {
+            dir: dir.clone(),
+            ttl: 0,
+        }));
+        assert!(!cache
+            .is_free_software("org.flatpak.Test", Some("MIT"), || Ok(false))
+            .unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
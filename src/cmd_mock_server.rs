@@ -0,0 +1,400 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use clap::Args;
+use log::{info, warn};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{cmd_validate::LicensePolicy, utils::glob_match};
+
+#[derive(Args, Debug)]
+pub struct MockServerArgs {
+    /// A JSON file with the responses to serve and the failures to inject. See `Scenario` for the format.
+    #[arg(long)]
+    scenario: PathBuf,
+    /// The address to listen on. Point both `flat_manager_url` and `backend_url` in the config at it.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: String,
+    /// Append every request the server receives to this file, one JSON object per line.
+    #[arg(long)]
+    record: Option<PathBuf>,
+}
+
+/// The data served by the mock server. Builds are keyed by build ID and storefront info by app ID; they are served
+/// as-is, so they should be in the same format as the real endpoints return. Missing entries are a 404.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Scenario {
+    pub builds: HashMap<String, Value>,
+    pub storefront_info: HashMap<String, Value>,
+    /// Stands in for the backend's is-free-software endpoint.
+    pub is_free_software: LicensePolicy,
+    /// The response to review requests. If not given, no review is required.
+    pub review_response: Option<Value>,
+    pub failures: Vec<InjectedFailure>,
+}
+
+/// Makes the server fail the next `times` requests that match `path` (a glob, see `utils::glob_match`) and `method`,
+/// so the hooks' retry logic can be tested. The server responds with `status`, or, if `hang_seconds` is set, waits
+/// that long and closes the connection without responding, so the client times out.
+#[derive(Debug, Deserialize)]
+pub struct InjectedFailure {
+    pub path: String,
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default = "default_failure_times")]
+    pub times: u32,
+    #[serde(default = "default_failure_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub hang_seconds: Option<u64>,
+}
+
+fn default_failure_times() -> u32 {
+    1
+}
+
+fn default_failure_status() -> u16 {
+    500
+}
+
+#[derive(Debug, Serialize)]
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    /// The body, parsed as JSON if possible.
+    body: Value,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Outcome {
+    Respond { status: u16, body: String },
+    Hang(Duration),
+}
+
+struct MockServer {
+    scenario: Scenario,
+    record: Option<File>,
+}
+
+impl MockServerArgs {
+    pub fn run(&self) -> Result<()> {
+        let scenario: Scenario = serde_json::from_reader(File::open(&self.scenario)?)?;
+
+        let record = match &self.record {
+            Some(path) => Some(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ),
+            None => None,
+        };
+
+        let server = Arc::new(Mutex::new(MockServer { scenario, record }));
+
+        let listener = TcpListener::bind(&self.listen)?;
+        info!("Mock server listening on {}", listener.local_addr()?);
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = server.clone();
+
+            /* Each connection gets its own thread, so a hanging response doesn't block the others */
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(&server, stream) {
+                    warn!("Failed to handle request: {e}");
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn handle_connection(server: &Mutex<MockServer>, mut stream: TcpStream) -> Result<()> {
+    let request = read_request(BufReader::new(&stream))?;
+    info!("{} {}", request.method, request.path);
+
+    /* Don't hold the lock while hanging */
+    let outcome = server.lock().unwrap().handle(&request)?;
+
+    match outcome {
+        Outcome::Respond { status, body } => {
+            write!(
+                stream,
+                "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                reason_phrase(status),
+                body.len()
+            )?;
+        }
+        Outcome::Hang(duration) => thread::sleep(duration),
+    }
+
+    Ok(())
+}
+
+fn read_request<R: BufRead>(mut reader: R) -> Result<Request> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    let (method, target) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        [method, target, _version] => (method.to_string(), target.to_string()),
+        _ => return Err(anyhow!("Malformed request line: {request_line:?}")),
+    };
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let body = String::from_utf8_lossy(&body).into_owned();
+
+    let url = Url::parse(&format!("http://localhost{target}"))?;
+
+    Ok(Request {
+        method,
+        path: url.path().to_string(),
+        query: url.query_pairs().into_owned().collect(),
+        body: serde_json::from_str(&body).unwrap_or(Value::String(body)),
+    })
+}
+
+impl MockServer {
+    fn handle(&mut self, request: &Request) -> Result<Outcome> {
+        if let Some(record) = &mut self.record {
+            writeln!(record, "{}", serde_json::to_string(request)?)?;
+            record.flush()?;
+        }
+
+        if let Some(failure) = self.scenario.failures.iter_mut().find(|failure| {
+            failure.times > 0
+                && glob_match(&failure.path, &request.path)
+                && failure
+                    .method
+                    .as_ref()
+                    .is_none_or(|method| method.eq_ignore_ascii_case(&request.method))
+        }) {
+            failure.times -= 1;
+            info!("Injecting failure for {} {}", request.method, request.path);
+
+            return Ok(match failure.hang_seconds {
+                Some(seconds) => Outcome::Hang(Duration::from_secs(seconds)),
+                None => Outcome::Respond {
+                    status: failure.status,
+                    body: r#"{"error": "injected failure"}"#.to_string(),
+                },
+            });
+        }
+
+        Ok(self.route(request))
+    }
+
+    fn route(&self, request: &Request) -> Outcome {
+        let ok = |value: &Value| Outcome::Respond {
+            status: 200,
+            body: value.to_string(),
+        };
+        let not_found = Outcome::Respond {
+            status: 404,
+            body: r#"{"error": "not found"}"#.to_string(),
+        };
+        let query = |key: &str| request.query.get(key).map(String::as_str);
+
+        let segments = request.path.split('/').collect::<Vec<_>>();
+        match (request.method.as_str(), &segments[..]) {
+            ("GET", ["", "api", "v1", "build", build_id, "extended"]) => {
+                match self.scenario.builds.get(*build_id) {
+                    Some(build) => ok(build),
+                    None => not_found,
+                }
+            }
+            ("POST", ["", "api", "v1", "job", _, "check", "review"]) => ok(&Value::Null),
+            ("GET", ["", "purchases", "storefront-info"]) => {
+                match query("app_id").and_then(|app_id| self.scenario.storefront_info.get(app_id)) {
+                    Some(info) => ok(info),
+                    None => not_found,
+                }
+            }
+            ("GET", ["", "purchases", "storefront-info", "is-free-software"]) => {
+                match query("app_id") {
+                    Some(app_id) => ok(&Value::Bool(
+                        self.scenario
+                            .is_free_software
+                            .is_free_software(app_id, query("license")),
+                    )),
+                    None => not_found,
+                }
+            }
+            ("POST", ["", "moderation", "submit_review_request"]) => ok(self
+                .scenario
+                .review_response
+                .as_ref()
+                .unwrap_or(&serde_json::json!({ "requires_review": false }))),
+            ("POST", ["", "emails", "build-notification"]) => ok(&Value::Null),
+            _ => not_found,
+        }
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        404 => "Not Found",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, target: &str, body: &str) -> Request {
+        read_request(
+            format!(
+                "{method} {target} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn server(scenario: &str) -> MockServer {
+        MockServer {
+            scenario: serde_json::from_str(scenario).unwrap(),
+            record: None,
+        }
+    }
+
+    fn respond(status: u16, body: &str) -> Outcome {
+        Outcome::Respond {
+            status,
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_read_request() {
+        let request = request(
+            "POST",
+            "/api/v1/job/2/check/review?foo=a%20b",
+            r#"{"new-status": {"status": "Pending"}}"#,
+        );
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/v1/job/2/check/review");
+        assert_eq!(request.query.get("foo").map(String::as_str), Some("a b"));
+        assert_eq!(
+            request.body,
+            serde_json::json!({"new-status": {"status": "Pending"}})
+        );
+    }
+
+    #[test]
+    fn test_routes() {
+        let mut server = server(
+            r#"{
+                "builds": {"1": {"build": {"app_id": "org.flatpak.Test"}}},
+                "storefront_info": {"org.flatpak.Test": {"is_free_software": true}},
+                "is_free_software": {"licenses": {"MIT": true}}
+            }"#,
+        );
+
+        let mut handle = |method, target| server.handle(&request(method, target, "")).unwrap();
+
+        assert_eq!(
+            handle("GET", "/api/v1/build/1/extended"),
+            respond(200, r#"{"build":{"app_id":"org.flatpak.Test"}}"#)
+        );
+        assert!(matches!(
+            handle("GET", "/api/v1/build/2/extended"),
+            Outcome::Respond { status: 404, .. }
+        ));
+        assert_eq!(
+            handle("GET", "/purchases/storefront-info?app_id=org.flatpak.Test"),
+            respond(200, r#"{"is_free_software":true}"#)
+        );
+        assert_eq!(
+            handle(
+                "GET",
+                "/purchases/storefront-info/is-free-software?app_id=org.flatpak.Test&license=MIT"
+            ),
+            respond(200, "true")
+        );
+        assert_eq!(
+            handle("POST", "/moderation/submit_review_request"),
+            respond(200, r#"{"requires_review":false}"#)
+        );
+        assert_eq!(
+            handle("POST", "/emails/build-notification"),
+            respond(200, "null")
+        );
+    }
+
+    #[test]
+    fn test_injected_failures() {
+        let mut server = server(
+            r#"{
+                "failures": [
+                    {"path": "/purchases/*", "times": 2, "status": 503},
+                    {"path": "/emails/build-notification", "method": "POST", "hang_seconds": 60}
+                ]
+            }"#,
+        );
+
+        let mut handle = |method, target| server.handle(&request(method, target, "")).unwrap();
+
+        let target = "/purchases/storefront-info?app_id=org.flatpak.Test";
+        assert!(matches!(
+            handle("GET", target),
+            Outcome::Respond { status: 503, .. }
+        ));
+        assert!(matches!(
+            handle("GET", target),
+            Outcome::Respond { status: 503, .. }
+        ));
+        assert!(matches!(
+            handle("GET", target),
+            Outcome::Respond { status: 404, .. }
+        ));
+
+        assert_eq!(
+            handle("POST", "/emails/build-notification"),
+            Outcome::Hang(Duration::from_secs(60))
+        );
+        assert_eq!(
+            handle("POST", "/emails/build-notification"),
+            respond(200, "null")
+        );
+    }
+}
@@ -1,43 +1,67 @@
 use std::{
-    collections::HashMap,
-    fs,
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    fmt,
     io::{Read, Write},
-    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, Result};
-use clap::Args;
+use clap::{Args, ValueEnum};
 use elementtree::Element;
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
-use log::info;
+use log::{info, warn};
 use ostree::{
-    gio::{Cancellable, File},
-    glib::VariantDict,
+    gio::{Cancellable, FileInfo, FileType, MemoryInputStream},
+    glib::{compute_checksum_for_data, Bytes, ChecksumType, Variant, VariantDict, VariantTy},
     prelude::Cast,
-    MutableTree, Repo,
+    MutableTree, ObjectType, Repo,
 };
+use serde::Serialize;
 
 use crate::{
-    config::{Config, RegularConfig, ValidateConfig},
+    config::{Config, ConfigArgs, FixtureConfig, RegularConfig},
     job_utils::BuildExtended,
+    provenance::Provenance,
     storefront::StorefrontInfo,
-    utils::{app_id_from_ref, mtree_lookup, mtree_lookup_file, read_file_from_repo, Transaction},
+    utils::{
+        app_id_from_ref, get_appstream_path, id_from_ref, load_appstream, load_commit_metadata,
+        mtree_lookup, mtree_lookup_file, open_repo, read_file_from_repo, Transaction,
+    },
 };
 
+const END_OF_LIFE_KEY: &str = "ostree.endoflife";
+const END_OF_LIFE_REBASE_KEY: &str = "ostree.endoflife-rebase";
+
 #[derive(Args, Debug)]
 pub struct PublishArgs {
-    /// Path to the config file. The script is usually run in the build directory, so this needs to be an absolute path.
-    #[arg(short, long)]
-    config: PathBuf,
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// Don't change anything, just print how each ref would be rewritten.
+    #[arg(long)]
+    dry_run: bool,
+    /// The format of the `--dry-run` report.
+    #[arg(long, value_enum, default_value_t = ReportFormat::Text, requires = "dry_run")]
+    format: ReportFormat,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ReportFormat {
+    Text,
+    Json,
 }
 
 impl PublishArgs {
     pub fn run(&self) -> Result<()> {
-        let config: RegularConfig = serde_json::from_reader(fs::File::open(self.config.clone())?)?;
+        match (&self.config.fixtures, &self.config.config) {
+            (Some(fixtures), _) => self.publish(&FixtureConfig::load(fixtures)?),
+            (None, Some(config)) => self.publish(&RegularConfig::load(config)?),
+            (None, None) => unreachable!("clap requires either --config or --fixtures"),
+        }
+    }
 
-        // Open the build repo at the current directory
-        let repo = Repo::new(&File::for_path("."));
-        repo.open(Cancellable::NONE)?;
+    fn publish<C: Config>(&self, config: &C) -> Result<()> {
+        // Open the build repo (the current directory, unless the config says otherwise)
+        let repo = open_repo(config.repo_path())?;
 
         let refs = repo.list_refs(None, Cancellable::NONE)?;
 
@@ -48,47 +72,714 @@ impl PublishArgs {
             Some(config.get_build()?)
         };
 
-        let mut storefront_infos = HashMap::new();
+        let mut report = PublishReport {
+            build_id: build.as_ref().and(config.get_build_id().ok()),
+            dry_run: self.dry_run,
+            ..Default::default()
+        };
+
+        /* Recorded in the provenance of every rewritten commit */
+        let rewrite_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        if self.dry_run {
+            let storefront_infos = fetch_storefront_infos(config, &refs)?;
+            for (refstring, checksum) in &refs {
+                let app_id = app_id_from_ref(refstring, config.app_suffixes());
+                report.add(plan_ref(
+                    &repo,
+                    &storefront_infos[&app_id],
+                    &build,
+                    report.build_id,
+                    rewrite_time,
+                    &app_id,
+                    refstring,
+                    checksum,
+                )?);
+            }
+            report.sort();
+
+            match self.format {
+                ReportFormat::Text => print!("{report}"),
+                ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+            }
+            return Ok(());
+        }
 
-        // Rewrite each one
-        for (refstring, checksum) in refs.into_iter() {
-            let refstring = refstring.to_string();
+        // Fetch the storefront info for every app before touching the repo, so a failure doesn't leave the build
+        // half-rewritten
+        let result = fetch_storefront_infos(config, &refs).and_then(|storefront_infos| {
+            rewrite_refs(
+                &repo,
+                &refs,
+                &storefront_infos,
+                &build,
+                rewrite_time,
+                config.app_suffixes(),
+                &mut report,
+            )
+        });
+        if let Err(e) = &result {
+            warn!("Publish failed, no refs were changed: {e}");
+            report.error = Some(e.to_string());
+        }
+        report.sort();
 
-            info!("Rewriting {refstring} ({checksum})");
+        if let Err(e) = config.post_publish_report(&report) {
+            /* Either the refs are already rewritten or the publish failed anyway, so don't fail over the report */
+            warn!("Failed to submit the publish report: {e}");
+        }
 
-            let app_id = app_id_from_ref(&refstring);
+        result
+    }
+}
 
-            let storefront_info = config.get_storefront_info(&app_id)?;
-            if !storefront_infos.contains_key(&app_id) {
-                storefront_infos.insert(app_id.clone(), storefront_info);
-            }
-            let storefront_info = storefront_infos.get(&app_id).unwrap();
+/// Fetches the storefront info for each app that has a ref in the repo.
+fn fetch_storefront_infos<C: Config>(
+    config: &C,
+    refs: &HashMap<String, String>,
+) -> Result<HashMap<String, StorefrontInfo>> {
+    let mut storefront_infos = HashMap::new();
+    for refstring in refs.keys() {
+        if let Entry::Vacant(entry) =
+            storefront_infos.entry(app_id_from_ref(refstring, config.app_suffixes()))
+        {
+            let storefront_info = config.get_storefront_info(entry.key())?;
+            entry.insert(storefront_info);
+        }
+    }
+    Ok(storefront_infos)
+}
+
+/// Rewrites all the refs in a single transaction, so that either all of them are updated or none are. The rewrites
+/// are added to the report as they are computed.
+fn rewrite_refs(
+    repo: &Repo,
+    refs: &HashMap<String, String>,
+    storefront_infos: &HashMap<String, StorefrontInfo>,
+    build: &Option<BuildExtended>,
+    rewrite_time: u64,
+    app_suffixes: &[String],
+    report: &mut PublishReport,
+) -> Result<()> {
+    let tx = Transaction::new(repo)?;
+
+    // Write all the new commits first
+    for (refstring, checksum) in refs {
+        info!("Rewriting {refstring} ({checksum})");
+        let app_id = app_id_from_ref(refstring, app_suffixes);
+        let build_id = report.build_id;
+        report.add(rewrite_ref(
+            repo,
+            &storefront_infos[&app_id],
+            build,
+            build_id,
+            rewrite_time,
+            &app_id,
+            refstring,
+            checksum,
+        )?);
+    }
+
+    // Then update the refs. They only change when the transaction is committed; if anything before that fails, the
+    // transaction is aborted and the refs stay as they were.
+    for rewrite in &report.refs {
+        info!(
+            "Rewriting ref {} from {} to {}",
+            rewrite.refstring, rewrite.checksum.old, rewrite.checksum.new
+        );
+        repo.transaction_set_ref(None, &rewrite.refstring, Some(&rewrite.checksum.new));
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// A record of what the publish hook changed (or, with `--dry-run`, would change), so the backend can keep a publish
+/// history for each app.
+#[derive(Debug, Default, Serialize)]
+pub struct PublishReport {
+    /// The build being published. Not set for republishes.
+    pub build_id: Option<i64>,
+    /// Whether this is a `--dry-run` report. The new checksums are estimates then: the real publish records its own
+    /// time in the provenance, which changes the checksums.
+    pub dry_run: bool,
+    pub refs: Vec<RefRewrite>,
+    /// Refs that were left alone.
+    pub skipped: Vec<SkippedRef>,
+    /// If the publish failed, the error. None of the refs were changed in that case, and `refs` lists the rewrites
+    /// that were rolled back.
+    pub error: Option<String>,
+}
 
-            rewrite_ref(&repo, storefront_info, &build, &refstring, &checksum)?;
+impl PublishReport {
+    fn add(&mut self, rewrite: RefRewrite) {
+        if rewrite.checksum.old == rewrite.checksum.new {
+            info!("No changes to {}", rewrite.refstring);
+            self.skipped.push(SkippedRef {
+                refstring: rewrite.refstring,
+                reason: "No changes".to_string(),
+            });
+        } else {
+            self.refs.push(rewrite);
         }
+    }
+
+    fn sort(&mut self) {
+        self.refs.sort_by(|a, b| a.refstring.cmp(&b.refstring));
+        self.skipped.sort_by(|a, b| a.refstring.cmp(&b.refstring));
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SkippedRef {
+    pub refstring: String,
+    pub reason: String,
+}
+
+/// The old and new values of something the publish hook rewrites.
+#[derive(Debug, Serialize)]
+pub struct Change<T> {
+    pub old: T,
+    pub new: T,
+}
+
+/// What the publish hook did to a ref.
+#[derive(Debug, Serialize)]
+pub struct RefRewrite {
+    pub refstring: String,
+    pub checksum: Change<String>,
+    /// A unified diff of the appstream catalog file, if it changed.
+    pub appstream_diff: Option<String>,
+    pub flathub_keys: KeyChanges,
+    pub subsets: Change<Vec<String>>,
+    pub token_type: Change<Option<i32>>,
+    pub end_of_life: Change<Option<String>>,
+    pub end_of_life_rebase: Change<Option<String>>,
+}
 
+/// The `flathub::` keys in the `<custom>` tags of an appstream file that were added, removed or changed.
+#[derive(Debug, Default, Serialize)]
+pub struct KeyChanges {
+    pub added: BTreeMap<String, String>,
+    pub removed: BTreeMap<String, String>,
+    pub changed: BTreeMap<String, Change<String>>,
+}
+
+impl KeyChanges {
+    fn between(old_appstream: &str, new_appstream: &str) -> Result<Self> {
+        let mut old = flathub_keys(old_appstream)?;
+        let new = flathub_keys(new_appstream)?;
+
+        let mut changes = Self::default();
+        for (key, value) in new {
+            match old.remove(&key) {
+                None => {
+                    changes.added.insert(key, value);
+                }
+                Some(old_value) if old_value != value => {
+                    changes.changed.insert(
+                        key,
+                        Change {
+                            old: old_value,
+                            new: value,
+                        },
+                    );
+                }
+                Some(_) => {}
+            }
+        }
+        changes.removed = old;
+
+        Ok(changes)
+    }
+}
+
+/// Lists the `flathub::` keys in the `<custom>` tags of an appstream file, with their values.
+fn flathub_keys(appstream: &str) -> Result<BTreeMap<String, String>> {
+    let root = Element::from_reader(appstream.as_bytes())?;
+
+    Ok(root
+        .find_all("component")
+        .flat_map(|component| component.find_all("custom"))
+        .flat_map(|custom| custom.find_all("value"))
+        .filter_map(|value| {
+            let key = value.get_attr("key")?;
+            key.to_lowercase()
+                .starts_with("flathub::")
+                .then(|| (key.to_string(), value.text().to_string()))
+        })
+        .collect())
+}
+
+impl fmt::Display for PublishReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.dry_run {
+            writeln!(
+                f,
+                "Dry run, nothing was changed. The new commit checksums are estimates, the real ones depend on the \
+                time of the publish.\n"
+            )?;
+        }
+        for rewrite in &self.refs {
+            write!(f, "{rewrite}")?;
+        }
+        for skipped in &self.skipped {
+            writeln!(f, "{} (skipped: {})", skipped.refstring, skipped.reason)?;
+        }
+        if let Some(error) = &self.error {
+            writeln!(f, "Failed, no refs were changed: {error}")?;
+        }
         Ok(())
     }
 }
 
-fn rewrite_ref(
+impl fmt::Display for RefRewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.refstring)?;
+        writeln!(
+            f,
+            "  commit: {} -> {}",
+            self.checksum.old, self.checksum.new
+        )?;
+        writeln!(
+            f,
+            "  xa.subsets: [{}] -> [{}]",
+            self.subsets.old.join(", "),
+            self.subsets.new.join(", ")
+        )?;
+        let token_type = |t: Option<i32>| t.map_or("none".to_string(), |t| t.to_string());
+        writeln!(
+            f,
+            "  xa.token-type: {} -> {}",
+            token_type(self.token_type.old),
+            token_type(self.token_type.new)
+        )?;
+        let string = |s: &Option<String>| s.clone().unwrap_or("none".to_string());
+        writeln!(
+            f,
+            "  ostree.endoflife: {} -> {}",
+            string(&self.end_of_life.old),
+            string(&self.end_of_life.new)
+        )?;
+        writeln!(
+            f,
+            "  ostree.endoflife-rebase: {} -> {}",
+            string(&self.end_of_life_rebase.old),
+            string(&self.end_of_life_rebase.new)
+        )?;
+        for (key, value) in &self.flathub_keys.added {
+            writeln!(f, "  + {key}: {value}")?;
+        }
+        for (key, value) in &self.flathub_keys.removed {
+            writeln!(f, "  - {key}: {value}")?;
+        }
+        for (key, Change { old, new }) in &self.flathub_keys.changed {
+            writeln!(f, "  ~ {key}: {old} -> {new}")?;
+        }
+        match &self.appstream_diff {
+            Some(diff) => write!(f, "{diff}")?,
+            None => writeln!(f, "  appstream: unchanged")?,
+        }
+        writeln!(f)
+    }
+}
+
+impl RefRewrite {
+    /// Describes a ref that the publish hook leaves alone.
+    fn unchanged(
+        refstring: &str,
+        app_id: &str,
+        checksum: &str,
+        metadata: &VariantDict,
+    ) -> Result<Self> {
+        Self::new(
+            refstring,
+            app_id,
+            Change {
+                old: checksum.to_string(),
+                new: checksum.to_string(),
+            },
+            None,
+            Change {
+                old: metadata,
+                new: metadata,
+            },
+        )
+    }
+
+    /// Describes a rewrite. `appstream` is the old and new contents of the appstream file of `app_id`, if it changed,
+    /// and the metadata is the commit metadata before and after `rewrite_metadata`.
+    fn new(
+        refstring: &str,
+        app_id: &str,
+        checksum: Change<String>,
+        appstream: Option<(&str, &str)>,
+        metadata: Change<&VariantDict>,
+    ) -> Result<Self> {
+        let (appstream_diff, flathub_keys) = match appstream {
+            Some((old, new)) => (
+                Some(unified_diff(old, new, &get_appstream_path(app_id))),
+                KeyChanges::between(old, new)?,
+            ),
+            None => (None, KeyChanges::default()),
+        };
+
+        Ok(Self {
+            refstring: refstring.to_string(),
+            checksum,
+            appstream_diff,
+            flathub_keys,
+            subsets: Change {
+                old: read_subsets(metadata.old),
+                new: read_subsets(metadata.new),
+            },
+            token_type: Change {
+                old: read_token_type(metadata.old),
+                new: read_token_type(metadata.new),
+            },
+            end_of_life: Change {
+                old: read_string(metadata.old, END_OF_LIFE_KEY),
+                new: read_string(metadata.new, END_OF_LIFE_KEY),
+            },
+            end_of_life_rebase: Change {
+                old: read_string(metadata.old, END_OF_LIFE_REBASE_KEY),
+                new: read_string(metadata.new, END_OF_LIFE_REBASE_KEY),
+            },
+        })
+    }
+}
+
+/// Adds the provenance (including the original commit's checksum, so `rollback` can restore it) to the metadata of a
+/// rewritten commit. If the rewrite doesn't change the appstream file or the metadata, nothing is added and `false` is
+/// returned, so the original commit can be kept as it is.
+fn record_provenance(
+    metadata: &VariantDict,
+    old_metadata: &VariantDict,
+    appstream_changed: bool,
+    provenance: &Provenance,
+) -> bool {
+    let changed = appstream_changed
+        || read_subsets(metadata) != read_subsets(old_metadata)
+        || read_token_type(metadata) != read_token_type(old_metadata)
+        || [END_OF_LIFE_KEY, END_OF_LIFE_REBASE_KEY]
+            .iter()
+            .any(|key| read_string(metadata, key) != read_string(old_metadata, key));
+
+    if changed {
+        provenance.record(metadata);
+    }
+
+    changed
+}
+
+fn read_subsets(metadata: &VariantDict) -> Vec<String> {
+    metadata
+        .lookup::<Vec<String>>("xa.subsets")
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+fn read_token_type(metadata: &VariantDict) -> Option<i32> {
+    metadata
+        .lookup::<i32>("xa.token-type")
+        .ok()
+        .flatten()
+        .map(i32::from_le)
+}
+
+fn read_string(metadata: &VariantDict, key: &str) -> Option<String> {
+    metadata.lookup::<String>(key).ok().flatten()
+}
+
+/// Works out what `rewrite_ref` would do to a ref, without writing anything to the repo.
+#[allow(clippy::too_many_arguments)]
+fn plan_ref(
     repo: &Repo,
     storefront_info: &StorefrontInfo,
     build: &Option<BuildExtended>,
+    build_id: Option<i64>,
+    rewrite_time: u64,
+    app_id: &str,
     refstring: &str,
     checksum: &str,
-) -> Result<()> {
-    let app_id = app_id_from_ref(refstring);
+) -> Result<RefRewrite> {
+    let appstream = match load_appstream(repo, app_id, checksum) {
+        Ok((appstream, _)) => {
+            let new_appstream =
+                rewrite_appstream_xml(storefront_info, refstring, build, &appstream)?;
+            (new_appstream != appstream).then_some((appstream, new_appstream))
+        }
+        /* rewrite_appstream_file skips refs without an appstream file, too */
+        Err(_) => None,
+    };
 
-    let tx = Transaction::new(repo)?;
+    let old_metadata = load_commit_metadata(repo, checksum)?;
+    let metadata = load_commit_metadata(repo, checksum)?;
+    rewrite_metadata(&metadata, storefront_info, app_id, refstring)?;
+
+    let provenance = Provenance::new(checksum, rewrite_time, build_id, storefront_info)?;
+    if !record_provenance(&metadata, &old_metadata, appstream.is_some(), &provenance) {
+        return RefRewrite::unchanged(refstring, app_id, checksum, &old_metadata);
+    }
+    let new_metadata = metadata.end();
+
+    let new_appstream_file = match &appstream {
+        Some((_, new_appstream)) => Some(gzip(new_appstream)?),
+        None => None,
+    };
+    let new_checksum = compute_rewritten_checksum(
+        repo,
+        checksum,
+        new_appstream_file
+            .as_deref()
+            .map(|data| (format!("{app_id}.xml.gz"), data)),
+        &new_metadata,
+    )?;
+
+    RefRewrite::new(
+        refstring,
+        app_id,
+        Change {
+            old: checksum.to_string(),
+            new: new_checksum,
+        },
+        appstream
+            .as_ref()
+            .map(|(old, new)| (old.as_str(), new.as_str())),
+        Change {
+            old: &old_metadata,
+            new: &VariantDict::new(Some(&new_metadata)),
+        },
+    )
+}
+
+/// Computes the checksum `rewrite_ref` would give a commit, without writing anything to the repo. `new_appstream` is
+/// the name and (compressed) contents of the new appstream file, if it changed, and `metadata` is the new commit
+/// metadata.
+fn compute_rewritten_checksum(
+    repo: &Repo,
+    checksum: &str,
+    new_appstream: Option<(String, &[u8])>,
+    metadata: &Variant,
+) -> Result<String> {
+    let commit = repo.load_commit(checksum)?.0;
+
+    let root_contents = ostree::checksum_from_bytes_v(&commit.child_value(6)).to_string();
+    let root_contents = match new_appstream {
+        Some((filename, data)) => replace_file_in_dirtree(
+            repo,
+            &root_contents,
+            &["files", "share", "app-info", "xmls"],
+            &filename,
+            &content_checksum(data)?,
+        )?,
+        None => root_contents,
+    };
+
+    /* Same layout as ostree_repo_write_commit_with_time(). The parent, subject, body and timestamp are copied, and
+    related objects are dropped. */
+    let new_commit = Variant::tuple_from_iter([
+        metadata.clone(),
+        commit.child_value(1),
+        Variant::array_from_iter_with_type(VariantTy::new("(say)")?, Vec::<Variant>::new()),
+        commit.child_value(3),
+        commit.child_value(4),
+        commit.child_value(5),
+        ostree::checksum_to_bytes_v(&root_contents),
+        commit.child_value(7),
+    ]);
+
+    Ok(sha256(&new_commit))
+}
+
+/// Computes the checksum of a dirtree after replacing one file in it (or in the subdirectory at `path`), and the
+/// checksums of the dirtrees above it.
+fn replace_file_in_dirtree(
+    repo: &Repo,
+    tree_checksum: &str,
+    path: &[&str],
+    filename: &str,
+    file_checksum: &str,
+) -> Result<String> {
+    let tree = repo.load_variant(ObjectType::DirTree, tree_checksum)?;
+    let (files, dirs) = (tree.child_value(0), tree.child_value(1));
+
+    let (files, dirs) = match path {
+        [] => (
+            replace_dirtree_entry(&files, filename, file_checksum)?,
+            dirs,
+        ),
+        [dir, rest @ ..] => {
+            let subtree = dirs
+                .iter()
+                .find(|entry| entry.child_value(0).str() == Some(dir))
+                .ok_or(anyhow!("directory {dir} not found"))?;
+            let subtree_checksum = replace_file_in_dirtree(
+                repo,
+                &ostree::checksum_from_bytes_v(&subtree.child_value(1)),
+                rest,
+                filename,
+                file_checksum,
+            )?;
+            (files, replace_dirtree_entry(&dirs, dir, &subtree_checksum)?)
+        }
+    };
+
+    Ok(sha256(&Variant::tuple_from_iter([files, dirs])))
+}
+
+/// Replaces the checksum of an entry in the file or subdirectory list of a dirtree. In both lists, the name is the
+/// first field and the content checksum is the second.
+fn replace_dirtree_entry(entries: &Variant, name: &str, checksum: &str) -> Result<Variant> {
+    let mut found = false;
+
+    let entries_type = entries.type_().element().to_owned();
+    let new_entries = entries
+        .iter()
+        .map(|entry| {
+            if entry.child_value(0).str() != Some(name) {
+                return entry;
+            }
 
+            found = true;
+            Variant::tuple_from_iter(entry.iter().enumerate().map(|(i, field)| {
+                if i == 1 {
+                    ostree::checksum_to_bytes_v(checksum)
+                } else {
+                    field
+                }
+            }))
+        })
+        .collect::<Vec<_>>();
+
+    if !found {
+        return Err(anyhow!("{name} not found"));
+    }
+
+    Ok(Variant::array_from_iter_with_type(
+        &entries_type,
+        new_entries,
+    ))
+}
+
+/// Computes the checksum of a file object, as `write_regfile_inline` would write it in `rewrite_appstream_file`.
+fn content_checksum(data: &[u8]) -> Result<String> {
+    let file_info = FileInfo::new();
+    file_info.set_file_type(FileType::Regular);
+    file_info.set_size(data.len() as i64);
+    file_info.set_attribute_uint32("unix::uid", 0);
+    file_info.set_attribute_uint32("unix::gid", 0);
+    file_info.set_attribute_uint32("unix::mode", 0o100644);
+
+    let stream = MemoryInputStream::from_bytes(&Bytes::from(data));
+    let checksum = ostree::checksum_file_from_input(
+        &file_info,
+        None,
+        Some(&stream),
+        ObjectType::File,
+        Cancellable::NONE,
+    )
+    .map_err(|e| anyhow!("Failed to checksum file: {e}"))?;
+
+    Ok(checksum.to_hex())
+}
+
+/// The checksum of a metadata object (commit, dirtree, etc.), which is the SHA-256 of its serialized normal form.
+fn sha256(variant: &Variant) -> String {
+    compute_checksum_for_data(ChecksumType::Sha256, variant.normal_form().data())
+        .unwrap()
+        .to_string()
+}
+
+fn gzip(content: &str) -> Result<Vec<u8>> {
+    let mut s = vec![];
+    GzEncoder::new(&mut s, Compression::default()).write_all(content.as_bytes())?;
+    Ok(s)
+}
+
+/// Formats a diff in unified diff format, with three lines of context around each change.
+fn unified_diff(old: &str, new: &str, path: &str) -> String {
+    const CONTEXT: usize = 3;
+
+    /* Unlike diff::lines(), str::lines() doesn't give a trailing newline its own empty line */
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    let lines = diff::slice(&old, &new);
+
+    /* Group the changes into hunks, merging hunks whose context would overlap */
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for (i, _) in lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, diff::Result::Both(..)))
+    {
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + CONTEXT + 1).min(lines.len());
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.1 => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    if hunks.is_empty() {
+        return String::new();
+    }
+
+    let mut result = format!("--- a/{path}\n+++ b/{path}\n");
+
+    for (start, end) in hunks {
+        let is_old = |line: &diff::Result<&&str>| !matches!(line, diff::Result::Right(_));
+        let is_new = |line: &diff::Result<&&str>| !matches!(line, diff::Result::Left(_));
+
+        let range = |before: usize, len: usize| {
+            /* Empty ranges start at the line before them */
+            let start = if len == 0 { before } else { before + 1 };
+            format!("{start},{len}")
+        };
+
+        result += &format!(
+            "@@ -{} +{} @@\n",
+            range(
+                lines[..start].iter().filter(|l| is_old(l)).count(),
+                lines[start..end].iter().filter(|l| is_old(l)).count()
+            ),
+            range(
+                lines[..start].iter().filter(|l| is_new(l)).count(),
+                lines[start..end].iter().filter(|l| is_new(l)).count()
+            ),
+        );
+
+        for line in &lines[start..end] {
+            result += &match line {
+                diff::Result::Left(l) => format!("-{l}\n"),
+                diff::Result::Both(b, _) => format!(" {b}\n"),
+                diff::Result::Right(r) => format!("+{r}\n"),
+            };
+        }
+    }
+
+    result
+}
+
+/// Writes the rewritten commit for a ref. This must be called in a transaction, and doesn't update the ref itself.
+#[allow(clippy::too_many_arguments)]
+fn rewrite_ref(
+    repo: &Repo,
+    storefront_info: &StorefrontInfo,
+    build: &Option<BuildExtended>,
+    build_id: Option<i64>,
+    rewrite_time: u64,
+    app_id: &str,
+    refstring: &str,
+    checksum: &str,
+) -> Result<RefRewrite> {
     // Create a MutableTree so we can edit the commit's files
     let mtree = MutableTree::from_commit(repo, checksum)?;
 
-    rewrite_appstream_file(repo, &mtree, &app_id, storefront_info, build, refstring)?;
-
-    // Write the modified MutableTree to the repository.
-    let repo_file = repo.write_mtree(&mtree, Cancellable::NONE)?;
+    let appstream =
+        rewrite_appstream_file(repo, &mtree, app_id, storefront_info, build, refstring)?;
 
     // Copy the original commit metadata. Leave out extended attributes, that's just the signature, which
     // won't be valid when we rewrite the commit (and flat-manager will sign the resulting commit with its own key
@@ -100,7 +791,17 @@ fn rewrite_ref(
     let time = ostree::commit_get_timestamp(&commit_metadata);
     let parent = ostree::commit_get_parent(&commit_metadata).map(|x| x.to_string());
 
-    rewrite_metadata(&metadata, storefront_info)?;
+    let old_metadata = commit_metadata.child_get::<VariantDict>(0);
+    rewrite_metadata(&metadata, storefront_info, app_id, refstring)?;
+
+    let provenance = Provenance::new(checksum, rewrite_time, build_id, storefront_info)?;
+    if !record_provenance(&metadata, &old_metadata, appstream.is_some(), &provenance) {
+        return RefRewrite::unchanged(refstring, app_id, checksum, &old_metadata);
+    }
+    let new_metadata = metadata.end();
+
+    // Write the modified MutableTree to the repository.
+    let repo_file = repo.write_mtree(&mtree, Cancellable::NONE)?;
 
     // Write a new commit with the new dirtree but (mostly) the same metadata
     let new_checksum = repo
@@ -108,26 +809,31 @@ fn rewrite_ref(
             parent.as_deref(),
             Some(subject),
             Some(body),
-            Some(&metadata.end()),
+            Some(&new_metadata),
             repo_file.dynamic_cast_ref().unwrap(),
             time,
             Cancellable::NONE,
         )?
         .to_string();
 
-    if checksum == new_checksum {
-        info!("No changes to {refstring}");
-    } else {
-        info!("Rewriting ref {refstring} from {checksum} to {new_checksum}");
-        // Update the ref to point to the edited commit
-        repo.transaction_set_ref(None, refstring, Some(&new_checksum));
-    }
-
-    tx.commit()?;
-
-    Ok(())
+    RefRewrite::new(
+        refstring,
+        app_id,
+        Change {
+            old: checksum.to_string(),
+            new: new_checksum,
+        },
+        appstream
+            .as_ref()
+            .map(|(old, new)| (old.as_str(), new.as_str())),
+        Change {
+            old: &old_metadata,
+            new: &VariantDict::new(Some(&new_metadata)),
+        },
+    )
 }
 
+/// Rewrites the appstream file in the given tree. Returns the old and new contents of the file, if it changed.
 pub fn rewrite_appstream_file(
     repo: &Repo,
     mtree: &MutableTree,
@@ -135,7 +841,7 @@ pub fn rewrite_appstream_file(
     storefront_info: &StorefrontInfo,
     build: &Option<BuildExtended>,
     refstring: &str,
-) -> Result<()> {
+) -> Result<Option<(String, String)>> {
     let appstream_filename = &format!("{app_id}.xml.gz");
     let appstream_file = mtree_lookup_file(
         mtree,
@@ -143,7 +849,7 @@ pub fn rewrite_appstream_file(
     );
 
     if appstream_file.is_err() {
-        return Ok(());
+        return Ok(None);
     }
 
     let appstream_content = read_file_from_repo(repo, &appstream_file.unwrap())?;
@@ -155,25 +861,21 @@ pub fn rewrite_appstream_file(
 
     if new_appstream == s {
         // If the appstream contents didn't change, we shouldn't bother rewriting the file
-        return Ok(());
+        return Ok(None);
     } else {
-        let difference = diff::lines(&s, &new_appstream)
-            .iter()
-            .map(|l| match l {
-                diff::Result::Left(l) => format!("-{l}\n"),
-                diff::Result::Both(b, _) => format!(" {b}\n"),
-                diff::Result::Right(r) => format!("+{r}\n"),
-            })
-            .collect::<String>();
-        info!("Changes to {}: {}", appstream_filename, difference);
+        info!(
+            "Changes to {}:\n{}",
+            appstream_filename,
+            unified_diff(&s, &new_appstream, &get_appstream_path(app_id))
+        );
     }
 
     // gzip encode the new appstream file
-    let mut s = vec![];
-    GzEncoder::new(&mut s, Compression::default()).write_all(new_appstream.as_bytes())?;
+    let compressed = gzip(&new_appstream)?;
 
     // Write the new appstream file to the repo
-    let checksum = repo.write_regfile_inline(None, 0, 0, 0o100644, None, &s, Cancellable::NONE)?;
+    let checksum =
+        repo.write_regfile_inline(None, 0, 0, 0o100644, None, &compressed, Cancellable::NONE)?;
 
     // Edit the MutableTree with a reference to the new appstream file
     mtree_lookup(mtree, &["files", "share", "app-info", "xmls"])?
@@ -181,7 +883,7 @@ pub fn rewrite_appstream_file(
         .ok_or(anyhow!("file not found"))?
         .replace_file(&format!("{app_id}.xml.gz"), &checksum)?;
 
-    Ok(())
+    Ok(Some((s, new_appstream)))
 }
 
 pub fn rewrite_appstream_xml(
@@ -358,7 +1060,12 @@ pub fn rewrite_appstream_xml(
 }
 
 /// Edits a commit's metadata according to the given storefront info.
-pub fn rewrite_metadata(metadata: &VariantDict, storefront_info: &StorefrontInfo) -> Result<()> {
+pub fn rewrite_metadata(
+    metadata: &VariantDict,
+    storefront_info: &StorefrontInfo,
+    app_id: &str,
+    refstring: &str,
+) -> Result<()> {
     let subsets = list_subsets(storefront_info);
 
     if subsets.is_empty() {
@@ -372,8 +1079,8 @@ pub fn rewrite_metadata(metadata: &VariantDict, storefront_info: &StorefrontInfo
         .pricing
         .as_ref()
         .map(|pricing| {
-            pricing.recommended_donation.map_or(false, |x| x > 0)
-                || pricing.minimum_payment.map_or(false, |x| x > 0)
+            pricing.recommended_donation.is_some_and(|x| x > 0)
+                || pricing.minimum_payment.is_some_and(|x| x > 0)
         })
         .unwrap_or(false);
 
@@ -384,9 +1091,48 @@ pub fn rewrite_metadata(metadata: &VariantDict, storefront_info: &StorefrontInfo
         metadata.remove("xa.token-type");
     }
 
+    // Only touch the end-of-life keys if the backend has an opinion on them, since they can also be set when the build
+    // is uploaded
+    match &storefront_info.end_of_life {
+        Some(Some(end_of_life)) => {
+            info!("Setting end of life: {end_of_life}");
+            metadata.insert(END_OF_LIFE_KEY, end_of_life);
+        }
+        Some(None) => {
+            metadata.remove(END_OF_LIFE_KEY);
+        }
+        None => {}
+    }
+
+    match &storefront_info.end_of_life_rebase {
+        Some(Some(new_app_id)) => {
+            let new_ref = rebase_ref(refstring, app_id, new_app_id);
+            info!("Setting end of life rebase: {new_ref}");
+            metadata.insert(END_OF_LIFE_REBASE_KEY, &new_ref);
+        }
+        Some(None) => {
+            metadata.remove(END_OF_LIFE_REBASE_KEY);
+        }
+        None => {}
+    }
+
     Ok(())
 }
 
+/// Gets the ref that replaces a ref when its app, `app_id`, is renamed, keeping any suffix like `.Locale`. For example,
+/// `runtime/org.example.Old.Locale/x86_64/stable` becomes `runtime/org.example.New.Locale/x86_64/stable`.
+fn rebase_ref(refstring: &str, app_id: &str, new_app_id: &str) -> String {
+    let id = id_from_ref(refstring);
+    let suffix = id.strip_prefix(app_id).unwrap_or_default();
+
+    let mut parts = refstring.split('/').collect::<Vec<_>>();
+    let new_id = format!("{new_app_id}{suffix}");
+    if let Some(part) = parts.get_mut(1) {
+        *part = &new_id;
+    }
+    parts.join("/")
+}
+
 /// Lists all the subsets that we should add to a commit, based on the given storefront info.
 fn list_subsets(storefront_info: &StorefrontInfo) -> Vec<String> {
     let mut subsets = vec![];
@@ -394,9 +1140,9 @@ fn list_subsets(storefront_info: &StorefrontInfo) -> Vec<String> {
     let verified = storefront_info
         .verification
         .as_ref()
-        .map_or(false, |x| x.verified);
+        .is_some_and(|x| x.verified);
 
-    let floss = storefront_info.is_free_software.map_or(false, |x| x);
+    let floss = storefront_info.is_free_software.unwrap_or(false);
 
     if verified {
         subsets.push("verified".to_string());
@@ -413,6 +1159,8 @@ fn list_subsets(storefront_info: &StorefrontInfo) -> Vec<String> {
 
 #[cfg(test)]
 mod tests {
+    use ostree::{gio::File, RepoMode};
+
     use crate::{
         job_utils::{Build, BuildRef},
         storefront::{PricingInfo, VerificationInfo},
@@ -433,6 +1181,7 @@ mod tests {
             }),
             pricing: None,
             is_free_software: Some(true),
+            ..Default::default()
         };
         let subsets = list_subsets(&storefront_info);
 
@@ -445,6 +1194,7 @@ mod tests {
             verification: None,
             pricing: None,
             is_free_software: Some(false),
+            ..Default::default()
         };
         let subsets = list_subsets(&storefront_info);
 
@@ -470,6 +1220,7 @@ mod tests {
             }),
             pricing: None,
             is_free_software: None,
+            ..Default::default()
         };
 
         let result = rewrite_appstream_xml(
@@ -521,6 +1272,7 @@ mod tests {
                 recommended_donation: Some(1),
             }),
             is_free_software: None,
+            ..Default::default()
         };
 
         let result = rewrite_appstream_xml(
@@ -581,6 +1333,7 @@ mod tests {
                 recommended_donation: None,
             }),
             is_free_software: None,
+            ..Default::default()
         };
 
         let result = rewrite_appstream_xml(
@@ -610,4 +1363,259 @@ mod tests {
 </components>"#,
         )
     }
+
+    #[test]
+    fn test_unified_diff() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\nn\n";
+
+        assert_eq!(
+            unified_diff(old, new, "test.xml"),
+            r#"--- a/test.xml
++++ b/test.xml
+@@ -1,5 +1,5 @@
+ a
+-b
++B
+ c
+ d
+ e
+@@ -11,3 +11,4 @@
+ k
+ l
+ m
++n
+"#
+        );
+        assert_eq!(unified_diff(old, old, "test.xml"), "");
+    }
+
+    #[test]
+    fn test_key_changes() {
+        let appstream = |values: &str| {
+            format!(
+                r#"<components><component><id>org.flatpak.Test</id><custom>{values}</custom></component></components>"#
+            )
+        };
+
+        let changes = KeyChanges::between(
+            &appstream(
+                r#"<value key="flathub::verification::verified">false</value>
+                <value key="flathub::pricing::minimum_payment">5</value>
+                <value key="flathub::manifest">https://example.com</value>
+                <value key="other">x</value>"#,
+            ),
+            &appstream(
+                r#"<value key="flathub::verification::verified">true</value>
+                <value key="flathub::manifest">https://example.com</value>
+                <value key="flathub::build::build_log_url">https://example.com/log</value>"#,
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(
+            changes.added,
+            BTreeMap::from([(
+                "flathub::build::build_log_url".to_string(),
+                "https://example.com/log".to_string()
+            )])
+        );
+        assert_eq!(
+            changes.removed,
+            BTreeMap::from([(
+                "flathub::pricing::minimum_payment".to_string(),
+                "5".to_string()
+            )])
+        );
+        assert_eq!(
+            changes.changed.keys().collect::<Vec<_>>(),
+            vec!["flathub::verification::verified"]
+        );
+        assert_eq!(
+            changes.changed["flathub::verification::verified"].new,
+            "true"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_metadata_end_of_life() {
+        let storefront_info: StorefrontInfo = serde_json::from_str(
+            r#"{"end_of_life": "Renamed to org.flatpak.NewTest", "end_of_life_rebase": "org.flatpak.NewTest"}"#,
+        )
+        .unwrap();
+
+        let metadata = VariantDict::new(None);
+        rewrite_metadata(
+            &metadata,
+            &storefront_info,
+            "org.flatpak.Test",
+            "runtime/org.flatpak.Test.Locale/x86_64/stable",
+        )
+        .unwrap();
+
+        assert_eq!(
+            read_string(&metadata, END_OF_LIFE_KEY).as_deref(),
+            Some("Renamed to org.flatpak.NewTest")
+        );
+        assert_eq!(
+            read_string(&metadata, END_OF_LIFE_REBASE_KEY).as_deref(),
+            Some("runtime/org.flatpak.NewTest.Locale/x86_64/stable")
+        );
+    }
+
+    #[test]
+    fn test_rewrite_metadata_end_of_life_cleared() {
+        let metadata = VariantDict::new(None);
+        metadata.insert(END_OF_LIFE_KEY, "Set when the build was uploaded");
+        metadata.insert(
+            END_OF_LIFE_REBASE_KEY,
+            "app/org.flatpak.NewTest/x86_64/stable",
+        );
+
+        /* If the backend doesn't know about end of life, the existing keys are kept */
+        rewrite_metadata(
+            &metadata,
+            &serde_json::from_str("{}").unwrap(),
+            "org.flatpak.Test",
+            "app/org.flatpak.Test/x86_64/stable",
+        )
+        .unwrap();
+        assert!(read_string(&metadata, END_OF_LIFE_KEY).is_some());
+        assert!(read_string(&metadata, END_OF_LIFE_REBASE_KEY).is_some());
+
+        /* If it clears them, they're removed */
+        rewrite_metadata(
+            &metadata,
+            &serde_json::from_str(r#"{"end_of_life": null, "end_of_life_rebase": null}"#).unwrap(),
+            "org.flatpak.Test",
+            "app/org.flatpak.Test/x86_64/stable",
+        )
+        .unwrap();
+        assert!(read_string(&metadata, END_OF_LIFE_KEY).is_none());
+        assert!(read_string(&metadata, END_OF_LIFE_REBASE_KEY).is_none());
+    }
+
+    #[test]
+    fn test_rebase_ref() {
+        assert_eq!(
+            rebase_ref(
+                "app/org.flatpak.Test/x86_64/stable",
+                "org.flatpak.Test",
+                "org.flatpak.NewTest"
+            ),
+            "app/org.flatpak.NewTest/x86_64/stable"
+        );
+        assert_eq!(
+            rebase_ref(
+                "runtime/org.flatpak.Test.Debug/aarch64/beta",
+                "org.flatpak.Test",
+                "org.flatpak.NewTest"
+            ),
+            "runtime/org.flatpak.NewTest.Debug/aarch64/beta"
+        );
+    }
+
+    #[test]
+    fn test_dry_run_checksum_matches_rewrite() {
+        let dir =
+            std::env::temp_dir().join(format!("flathub-hooks-publish-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        /* A minimal app, with another file next to the appstream file and in the directories above it */
+        let tree = dir.join("tree");
+        let xmls = tree.join("files/share/app-info/xmls");
+        std::fs::create_dir_all(&xmls).unwrap();
+        std::fs::create_dir_all(tree.join("files/bin")).unwrap();
+        std::fs::write(
+            xmls.join("org.flatpak.Test.xml.gz"),
+            gzip(
+                r#"<?xml version="1.0" encoding="utf-8"?>
+<components>
+    <component>
+        <id>org.flatpak.Test</id>
+    </component>
+</components>"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        std::fs::write(xmls.join("org.flatpak.Test.Plugin.xml.gz"), b"").unwrap();
+        std::fs::write(tree.join("files/bin/test"), "#!/bin/sh\n").unwrap();
+        std::fs::write(
+            tree.join("metadata"),
+            "[Application]\nname=org.flatpak.Test\n",
+        )
+        .unwrap();
+
+        let repo = Repo::new_for_path(dir.join("repo"));
+        repo.create(RepoMode::Archive, Cancellable::NONE).unwrap();
+
+        let refstring = "app/org.flatpak.Test/x86_64/stable";
+        let tx = Transaction::new(&repo).unwrap();
+        let mtree = MutableTree::new();
+        repo.write_directory_to_mtree(&File::for_path(&tree), &mtree, None, Cancellable::NONE)
+            .unwrap();
+        let root = repo.write_mtree(&mtree, Cancellable::NONE).unwrap();
+        let checksum = repo
+            .write_commit_with_time(
+                None,
+                Some("Build org.flatpak.Test"),
+                None,
+                Some(&VariantDict::new(None).end()),
+                root.dynamic_cast_ref().unwrap(),
+                1_700_000_000,
+                Cancellable::NONE,
+            )
+            .unwrap()
+            .to_string();
+        repo.transaction_set_ref(None, refstring, Some(&checksum));
+        tx.commit().unwrap();
+
+        /* Changes both the appstream file and the commit metadata */
+        let storefront_info = StorefrontInfo {
+            verification: Some(VerificationInfo {
+                verified: true,
+                ..Default::default()
+            }),
+            pricing: Some(PricingInfo {
+                recommended_donation: Some(5),
+                minimum_payment: None,
+            }),
+            ..Default::default()
+        };
+        let rewrite_time = 1_800_000_000;
+
+        let planned = plan_ref(
+            &repo,
+            &storefront_info,
+            &None,
+            Some(1),
+            rewrite_time,
+            "org.flatpak.Test",
+            refstring,
+            &checksum,
+        )
+        .unwrap();
+
+        let tx = Transaction::new(&repo).unwrap();
+        let rewritten = rewrite_ref(
+            &repo,
+            &storefront_info,
+            &None,
+            Some(1),
+            rewrite_time,
+            "org.flatpak.Test",
+            refstring,
+            &checksum,
+        )
+        .unwrap();
+        tx.commit().unwrap();
+
+        assert_ne!(rewritten.checksum.new, checksum);
+        assert_eq!(planned.checksum.new, rewritten.checksum.new);
+        assert_eq!(planned.appstream_diff, rewritten.appstream_diff);
+        assert_eq!(planned.token_type.new, Some(1));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -1,20 +1,23 @@
-use std::{fs, path::PathBuf};
-
 use anyhow::Result;
 use clap::Args;
 
-use crate::{config::RegularConfig, review::do_review};
+use crate::{
+    config::{ConfigArgs, FixtureConfig, RegularConfig},
+    review::do_review,
+};
 
 #[derive(Args, Debug)]
 pub struct ReviewArgs {
-    /// Path to the config file. The script is usually run in the build directory, so this needs to be an absolute path.
-    #[arg(short, long)]
-    config: PathBuf,
+    #[command(flatten)]
+    config: ConfigArgs,
 }
 
 impl ReviewArgs {
     pub fn run(&self) -> Result<()> {
-        let config: RegularConfig = serde_json::from_reader(fs::File::open(self.config.clone())?)?;
-        do_review(&config)
+        match (&self.config.fixtures, &self.config.config) {
+            (Some(fixtures), _) => do_review(&FixtureConfig::load(fixtures)?),
+            (None, Some(config)) => do_review(&RegularConfig::load(config)?),
+            (None, None) => unreachable!("clap requires either --config or --fixtures"),
+        }
     }
 }
@@ -0,0 +1,82 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use clap::Args;
+use log::info;
+use ostree::gio::Cancellable;
+
+use crate::{
+    provenance::Provenance,
+    utils::{glob_match, load_commit_metadata, open_repo, Transaction},
+};
+
+#[derive(Args, Debug)]
+pub struct RollbackArgs {
+    /// Path to the repo to roll back.
+    #[arg(long, default_value = ".")]
+    repo: PathBuf,
+    /// Only roll back refs that match this glob (`*` and `?` are supported). Can be given multiple times.
+    #[arg(long = "ref")]
+    refs: Vec<String>,
+    /// Only print which refs would be rolled back, without changing anything.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl RollbackArgs {
+    pub fn run(&self) -> Result<()> {
+        let repo = open_repo(&self.repo)?;
+
+        let mut refs = repo
+            .list_refs(None, Cancellable::NONE)?
+            .into_iter()
+            .filter(|(refstring, _)| {
+                self.refs.is_empty()
+                    || self
+                        .refs
+                        .iter()
+                        .any(|pattern| glob_match(pattern, refstring))
+            })
+            .collect::<Vec<_>>();
+        refs.sort();
+
+        // Find the commit each ref pointed to before the publish hook rewrote it
+        let mut rollbacks = vec![];
+        for (refstring, checksum) in refs {
+            let original = Provenance::load(&load_commit_metadata(&repo, &checksum)?)
+                .map(|provenance| provenance.original_commit);
+
+            match original {
+                Some(original) => {
+                    /* The original commit is gone if the repo has been pruned since the publish */
+                    repo.load_commit(&original).map_err(|e| {
+                        anyhow!("Original commit {original} of {refstring} is not in the repo: {e}")
+                    })?;
+                    rollbacks.push((refstring, checksum, original));
+                }
+                None => info!(
+                    "{refstring} ({checksum}) was not rewritten by the publish hook, skipping"
+                ),
+            }
+        }
+
+        for (refstring, checksum, original) in &rollbacks {
+            println!("{refstring}: {checksum} -> {original}");
+        }
+
+        if self.dry_run || rollbacks.is_empty() {
+            return Ok(());
+        }
+
+        // Update all the refs in one transaction, so either all of them are rolled back or none are
+        let tx = Transaction::new(&repo)?;
+        for (refstring, _, original) in &rollbacks {
+            repo.transaction_set_ref(None, refstring, Some(original));
+        }
+        tx.commit()?;
+
+        info!("Rolled back {} refs", rollbacks.len());
+
+        Ok(())
+    }
+}
@@ -1,32 +1,136 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
 use anyhow::{Ok, Result};
 use clap::Args;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::ValidateConfig,
+    config::{load_exceptions, LinterConfig, ValidateConfig, DEFAULT_SCREENSHOT_MIRROR_URL},
     job_utils::{Build, BuildExtended},
-    review::do_validation,
+    provenance::Provenance,
+    review::{diagnostics::CheckResult, do_validation, exceptions::Exceptions},
+    skiplist::{default_skiplist, SkiplistEntry},
+    utils::{glob_match, load_commit_metadata, DEFAULT_APP_SUFFIXES},
 };
 
 #[derive(Args, Debug)]
-pub struct ValidateArgs {}
+pub struct ValidateArgs {
+    /// Path to the repo to validate.
+    #[arg(long, default_value = ".")]
+    repo: PathBuf,
+    /// Only validate refs that match this glob (`*` and `?` are supported). Can be given multiple times.
+    #[arg(long = "ref")]
+    refs: Vec<String>,
+    /// A JSON file with the build info, in the format returned by flat-manager's `/api/v1/build/{id}/extended`
+    /// endpoint. Without it, the build is assumed to have no app ID or build log URLs.
+    #[arg(long)]
+    build_info: Option<PathBuf>,
+    /// Validate every app as if it were free software, which, for example, requires a build log URL.
+    #[arg(long, conflicts_with = "license_policy")]
+    assume_free_software: bool,
+    /// A JSON file that decides which apps are free software. See `LicensePolicy` for the format.
+    #[arg(long)]
+    license_policy: Option<PathBuf>,
+    /// A JSON file with a list of apps that are exempt from some or all of the checks. By default, the same built-in
+    /// list as the review hook is used.
+    #[arg(long)]
+    skiplist: Option<PathBuf>,
+    /// A JSON file with findings that are waived for specific apps, in the format `{"app_id": {"rule_id": "reason"}}`.
+    #[arg(long)]
+    exceptions: Option<PathBuf>,
+    /// The last component of the IDs of refs that go along with an app rather than being one, like `Locale`. Can be
+    /// given multiple times, and replaces the default list.
+    #[arg(long = "app-suffix", default_values = DEFAULT_APP_SUFFIXES)]
+    app_suffixes: Vec<String>,
+    /// The URL flatpak-builder's `--mirror-screenshots-url` was set to when the repo was built.
+    #[arg(long, default_value = DEFAULT_SCREENSHOT_MIRROR_URL)]
+    screenshot_mirror_url: String,
+    #[command(flatten)]
+    linter: LinterConfig,
+}
+
+/// Stands in for the backend's is-free-software endpoint. An app is free software if its ID is listed in `app_ids`,
+/// otherwise if its license is listed in `licenses`, otherwise `default` is used.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LicensePolicy {
+    pub app_ids: HashMap<String, bool>,
+    pub licenses: HashMap<String, bool>,
+    pub default: bool,
+}
+
+impl LicensePolicy {
+    pub fn is_free_software(&self, app_id: &str, license: Option<&str>) -> bool {
+        self.app_ids
+            .get(app_id)
+            .or_else(|| license.and_then(|license| self.licenses.get(license)))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// The output of `validate`: the validation results, plus the provenance of any refs that were rewritten by the
+/// publish hook (i.e. when validating a published repo).
+#[derive(Serialize)]
+struct ValidateOutput {
+    #[serde(flatten)]
+    result: CheckResult,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    provenance: BTreeMap<String, Provenance>,
+}
 
 impl ValidateArgs {
     pub fn run(&self) -> Result<()> {
-        let (_repo, _refs, result) = do_validation(self)?;
+        let (repo, refs, result) = do_validation(self)?;
+
+        let mut provenance = BTreeMap::new();
+        for (refstring, checksum) in refs {
+            if !self.should_validate_ref(&refstring) {
+                continue;
+            }
+            if let Some(p) = Provenance::load(&load_commit_metadata(&repo, &checksum)?) {
+                provenance.insert(refstring, p);
+            }
+        }
 
         /* Print the results */
-        println!("{}", serde_json::to_string_pretty(&result)?);
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&ValidateOutput { result, provenance })?
+        );
 
         Ok(())
     }
 }
 
 impl ValidateConfig for ValidateArgs {
-    fn get_is_free_software(&self, _app_id: &str, _license: Option<&str>) -> Result<bool> {
-        Ok(false)
+    fn get_is_free_software(&self, app_id: &str, license: Option<&str>) -> Result<bool> {
+        if self.assume_free_software {
+            return Ok(true);
+        }
+
+        match &self.license_policy {
+            Some(path) => {
+                let policy: LicensePolicy = serde_json::from_reader(fs::File::open(path)?)?;
+                Ok(policy.is_free_software(app_id, license))
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn has_license_backend(&self) -> bool {
+        self.license_policy.is_some()
     }
 
     fn get_build(&self) -> Result<BuildExtended> {
+        if let Some(path) = &self.build_info {
+            return Ok(serde_json::from_reader(fs::File::open(path)?)?);
+        }
+
         Ok(BuildExtended {
             build: Build {
                 app_id: None,
@@ -36,4 +140,39 @@ impl ValidateConfig for ValidateArgs {
             build_refs: vec![],
         })
     }
+
+    fn linter_config(&self) -> &LinterConfig {
+        &self.linter
+    }
+
+    fn screenshot_mirror_url(&self) -> &str {
+        &self.screenshot_mirror_url
+    }
+
+    fn app_suffixes(&self) -> &[String] {
+        &self.app_suffixes
+    }
+
+    fn skiplist(&self) -> Result<Vec<SkiplistEntry>> {
+        match &self.skiplist {
+            Some(path) => Ok(serde_json::from_reader(fs::File::open(path)?)?),
+            None => Ok(default_skiplist()),
+        }
+    }
+
+    fn exceptions(&self) -> Result<Exceptions> {
+        load_exceptions(self.exceptions.as_deref())
+    }
+
+    fn repo_path(&self) -> &Path {
+        &self.repo
+    }
+
+    fn should_validate_ref(&self, refstring: &str) -> bool {
+        self.refs.is_empty()
+            || self
+                .refs
+                .iter()
+                .any(|pattern| glob_match(pattern, refstring))
+    }
 }
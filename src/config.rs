@@ -1,22 +1,131 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
 use anyhow::{anyhow, Result};
+use clap::{ArgAction, Args};
 use log::info;
 use reqwest::blocking::Client;
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
+    cache::{BackendCache, DiskCacheConfig},
+    cmd_publish::PublishReport,
+    cmd_validate::LicensePolicy,
     job_utils::{BuildExtended, BuildNotificationRequest, CheckStatus, ReviewRequestArgs},
     review::{
         diagnostics::CheckResult,
+        exceptions::Exceptions,
         moderation::{ReviewRequest, ReviewRequestResponse},
     },
+    skiplist::{default_skiplist, SkiplistEntry},
     storefront::{get_is_free_software, StorefrontInfo},
-    utils::retry,
+    utils::{default_app_suffixes, retry},
 };
 
 /// Services for the validation step.
 pub trait ValidateConfig {
     fn get_is_free_software(&self, app_id: &str, license: Option<&str>) -> Result<bool>;
+    /// Whether `get_is_free_software` asks the backend (or something standing in for it). If so, its answers are
+    /// compared with the classification of the app's SPDX license expression.
+    fn has_license_backend(&self) -> bool;
     fn get_build(&self) -> Result<BuildExtended>;
+    fn linter_config(&self) -> &LinterConfig;
+
+    /// Path to the build repo. The hooks are run in the build directory, so this is the current directory by default.
+    fn repo_path(&self) -> &Path {
+        Path::new(".")
+    }
+
+    /// Apps that are exempt from some or all of the validations.
+    fn skiplist(&self) -> Result<Vec<SkiplistEntry>>;
+
+    /// Findings that are waived for specific apps.
+    fn exceptions(&self) -> Result<Exceptions>;
+
+    /// The URL flatpak-builder's `--mirror-screenshots-url` was set to. Screenshot URLs in the appstream data are
+    /// checked against the screenshots branch relative to this URL.
+    fn screenshot_mirror_url(&self) -> &str;
+
+    /// The last components of the IDs of refs that go along with an app rather than being one, like `Locale`.
+    fn app_suffixes(&self) -> &[String];
+
+    /// Whether the given ref should be validated. All refs are validated by default.
+    fn should_validate_ref(&self, _refstring: &str) -> bool {
+        true
+    }
+}
+
+pub const DEFAULT_SCREENSHOT_MIRROR_URL: &str = "https://dl.flathub.org/repo/screenshots";
+
+fn default_screenshot_mirror_url() -> String {
+    DEFAULT_SCREENSHOT_MIRROR_URL.to_string()
+}
+
+pub const DEFAULT_LINTER_COMMAND: &str =
+    "flatpak run --command=flatpak-builder-lint org.flatpak.Builder";
+
+/// Settings for running flatpak-builder-lint. Used both in the config file and as command line flags of `validate`.
+#[derive(Args, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LinterConfig {
+    /// The command used to run flatpak-builder-lint, split on whitespace. Use `flatpak-builder-lint` if the linter is
+    /// installed directly rather than through the org.flatpak.Builder flatpak.
+    #[arg(long = "linter-command", default_value = DEFAULT_LINTER_COMMAND)]
+    pub command: String,
+    /// Extra arguments to pass to the linter, before the `repo` subcommand.
+    #[arg(long = "linter-arg", allow_hyphen_values = true)]
+    pub extra_args: Vec<String>,
+    /// How long to let the linter run, in seconds, before killing it.
+    #[arg(long = "linter-timeout", default_value_t = DEFAULT_LINTER_TIMEOUT)]
+    pub timeout: u64,
+    /// Whether to pass `--exceptions` to the linter, so that Flathub's exceptions list is honored.
+    #[arg(long = "linter-no-exceptions", action = ArgAction::SetFalse)]
+    pub exceptions: bool,
+    /// Don't run any external linters, only the checks built into this program.
+    #[arg(long = "skip-external-linters")]
+    pub skip_external_linters: bool,
+}
+
+const DEFAULT_LINTER_TIMEOUT: u64 = 900;
+
+impl Default for LinterConfig {
+    fn default() -> Self {
+        Self {
+            command: DEFAULT_LINTER_COMMAND.to_string(),
+            extra_args: vec![],
+            timeout: DEFAULT_LINTER_TIMEOUT,
+            exceptions: true,
+            skip_external_linters: false,
+        }
+    }
+}
+
+/// Selects where the `publish` and `review` hooks get their config from.
+#[derive(Args, Debug)]
+pub struct ConfigArgs {
+    /// Path to the config file. The script is usually run in the build directory, so this needs to be an absolute path.
+    #[arg(
+        short,
+        long,
+        required_unless_present = "fixtures",
+        conflicts_with = "fixtures"
+    )]
+    pub config: Option<PathBuf>,
+    /// Path to a fixtures directory to use instead of flat-manager and the backend. See `FixtureConfig` for the layout.
+    #[arg(long)]
+    pub fixtures: Option<PathBuf>,
+}
+
+/// Reads an exceptions list from a JSON file. Without a file, there are no exceptions.
+pub fn load_exceptions(path: Option<&Path>) -> Result<Exceptions> {
+    match path {
+        Some(path) => Ok(serde_json::from_reader(File::open(path).map_err(|e| {
+            anyhow!("Failed to open exceptions file {}: {}", path.display(), e)
+        })?)?),
+        None => Ok(Exceptions::new()),
+    }
 }
 
 pub trait Config: ValidateConfig {
@@ -24,6 +133,8 @@ pub trait Config: ValidateConfig {
     fn get_job_id(&self) -> Result<i64>;
     fn get_is_republish(&self) -> Result<bool>;
     fn validation_observe_only(&self) -> bool;
+    /// Path to a local copy of the production repo, used to compare builds with what is currently published.
+    fn production_repo(&self) -> Option<&Path>;
 
     fn get_storefront_info(&self, app_id: &str) -> Result<StorefrontInfo>;
 
@@ -57,23 +168,66 @@ pub trait Config: ValidateConfig {
 
     fn post_review_request(&self, request: ReviewRequest) -> Result<ReviewRequestResponse>;
     fn post_email_notification(&self, result: &CheckResult) -> Result<()>;
+
+    /// Saves the record of what the publish hook changed.
+    fn post_publish_report(&self, report: &PublishReport) -> Result<()>;
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Deserialize)]
 pub struct RegularConfig {
     pub backend_url: String,
     pub flat_manager_url: String,
     pub flat_manager_token: String,
     #[serde(default)]
     pub validation_observe_only: bool,
+    #[serde(default)]
+    pub production_repo: Option<PathBuf>,
+    #[serde(default)]
+    pub linter: LinterConfig,
+    #[serde(default = "default_screenshot_mirror_url")]
+    pub screenshot_mirror_url: String,
+    #[serde(default = "default_skiplist")]
+    pub skiplist: Vec<SkiplistEntry>,
+    /// The last components of the IDs of Locale, Debug, Sources and similar refs, which go along with an app rather
+    /// than being one.
+    #[serde(default = "default_app_suffixes")]
+    pub app_suffixes: Vec<String>,
+    /// A JSON file with the exceptions list. It is read on every run, so it can be updated without changing the config.
+    #[serde(default)]
+    pub exceptions_file: Option<PathBuf>,
+    /// Where the publish hook writes its report of what it changed. If not set, the report isn't written to a file.
+    #[serde(default)]
+    pub publish_report_path: Option<PathBuf>,
+    /// A backend endpoint, relative to `backend_url`, to POST the publish report to. If not set, the report isn't
+    /// sent to the backend.
+    #[serde(default)]
+    pub publish_report_endpoint: Option<String>,
+    /// Keep the backend's storefront info and is-free-software answers on disk, so later runs can reuse them. They
+    /// are always cached in memory for the rest of the run.
+    #[serde(default)]
+    pub cache: Option<DiskCacheConfig>,
+    #[serde(skip)]
+    backend_cache: BackendCache,
 }
 
-impl RegularConfig {}
+impl RegularConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut config: Self = serde_json::from_reader(File::open(path)?)?;
+        config.backend_cache = BackendCache::new(config.cache.clone());
+        Ok(config)
+    }
+}
 
 impl ValidateConfig for RegularConfig {
     /// Uses a backend endpoint to determine if an app is FOSS based on its ID and license.
     fn get_is_free_software(&self, app_id: &str, license: Option<&str>) -> Result<bool> {
-        get_is_free_software(&self.backend_url, app_id, license)
+        self.backend_cache.is_free_software(app_id, license, || {
+            get_is_free_software(&self.backend_url, app_id, license)
+        })
+    }
+
+    fn has_license_backend(&self) -> bool {
+        true
     }
 
     fn get_build(&self) -> Result<BuildExtended> {
@@ -92,6 +246,26 @@ impl ValidateConfig for RegularConfig {
         })?;
         Ok(build)
     }
+
+    fn linter_config(&self) -> &LinterConfig {
+        &self.linter
+    }
+
+    fn screenshot_mirror_url(&self) -> &str {
+        &self.screenshot_mirror_url
+    }
+
+    fn app_suffixes(&self) -> &[String] {
+        &self.app_suffixes
+    }
+
+    fn skiplist(&self) -> Result<Vec<SkiplistEntry>> {
+        Ok(self.skiplist.clone())
+    }
+
+    fn exceptions(&self) -> Result<Exceptions> {
+        load_exceptions(self.exceptions_file.as_deref())
+    }
 }
 
 impl Config for RegularConfig {
@@ -111,8 +285,13 @@ impl Config for RegularConfig {
         self.validation_observe_only
     }
 
+    fn production_repo(&self) -> Option<&Path> {
+        self.production_repo.as_deref()
+    }
+
     fn get_storefront_info(&self, app_id: &str) -> Result<StorefrontInfo> {
-        StorefrontInfo::fetch(&self.backend_url, app_id)
+        self.backend_cache
+            .storefront_info(app_id, || StorefrontInfo::fetch(&self.backend_url, app_id))
     }
 
     fn set_check_status(&self, args: &ReviewRequestArgs) -> Result<()> {
@@ -191,4 +370,224 @@ impl Config for RegularConfig {
 
         Ok(())
     }
+
+    fn post_publish_report(&self, report: &PublishReport) -> Result<()> {
+        if let Some(path) = &self.publish_report_path {
+            info!("Writing publish report to {}", path.display());
+            fs::write(path, serde_json::to_string_pretty(report)?)?;
+        }
+
+        if let Some(endpoint) = &self.publish_report_endpoint {
+            let endpoint = format!("{}{}", self.backend_url, endpoint);
+            let convert_err = |e| anyhow!("Failed to contact backend {}: {}", &endpoint, e);
+
+            info!("Submitting publish report");
+
+            retry(|| {
+                Client::new()
+                    .post(&endpoint)
+                    .bearer_auth(&self.flat_manager_token)
+                    .json(report)
+                    .send()
+                    .map_err(convert_err)?
+                    .error_for_status()
+                    .map_err(convert_err)
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A config that reads everything it would otherwise get from flat-manager and the backend from a directory of fixture
+/// files, and writes everything it would send to them to the `output` subdirectory. This allows running the hooks
+/// end-to-end in CI. The directory contains:
+///
+/// - `config.json`: the rest of the settings (see the fields below). Optional.
+/// - `build.json`: the build, in the format of flat-manager's `/api/v1/build/{id}/extended` endpoint.
+/// - `storefront-info/{app_id}.json`: the storefront info for each app. Missing apps are treated as new apps.
+/// - `is-free-software.json`: a `LicensePolicy` that stands in for the backend's is-free-software endpoint. Optional;
+///   without it, no app is free software.
+/// - `review-response.json`: the response to the moderation review request. Optional; without it, no review is
+///   required.
+///
+/// The output files are `check-status.json`, `review-request.json`, `email-notification.json` and
+/// `publish-report.json`.
+#[derive(Deserialize)]
+pub struct FixtureConfig {
+    #[serde(skip)]
+    dir: PathBuf,
+    #[serde(default)]
+    pub build_id: i64,
+    #[serde(default)]
+    pub job_id: i64,
+    #[serde(default)]
+    pub is_republish: bool,
+    #[serde(default)]
+    pub validation_observe_only: bool,
+    /// Path to the build repo, relative to the current directory.
+    #[serde(default = "default_repo_path")]
+    pub repo: PathBuf,
+    #[serde(default)]
+    pub production_repo: Option<PathBuf>,
+    #[serde(default)]
+    pub linter: LinterConfig,
+    #[serde(default = "default_screenshot_mirror_url")]
+    pub screenshot_mirror_url: String,
+    #[serde(default = "default_skiplist")]
+    pub skiplist: Vec<SkiplistEntry>,
+    /// The last components of the IDs of Locale, Debug, Sources and similar refs, which go along with an app rather
+    /// than being one.
+    #[serde(default = "default_app_suffixes")]
+    pub app_suffixes: Vec<String>,
+    #[serde(default)]
+    pub exceptions_file: Option<PathBuf>,
+}
+
+fn default_repo_path() -> PathBuf {
+    PathBuf::from(".")
+}
+
+impl FixtureConfig {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut config: Self =
+            Self::read_json(&dir.join("config.json"))?.unwrap_or(serde_json::from_str("{}")?);
+        config.dir = dir.to_path_buf();
+        Ok(config)
+    }
+
+    fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let file = File::open(path)
+            .map_err(|e| anyhow!("Failed to open fixture {}: {}", path.display(), e))?;
+        Ok(Some(serde_json::from_reader(file).map_err(|e| {
+            anyhow!("Failed to parse fixture {}: {}", path.display(), e)
+        })?))
+    }
+
+    fn read_fixture<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        Self::read_json(&self.dir.join(name))
+    }
+
+    fn write_output<T: Serialize>(&self, name: &str, value: &T) -> Result<()> {
+        let output_dir = self.dir.join("output");
+        fs::create_dir_all(&output_dir)?;
+
+        let path = output_dir.join(name);
+        info!("Writing {}", path.display());
+        fs::write(path, serde_json::to_string_pretty(value)?)?;
+        Ok(())
+    }
+}
+
+impl ValidateConfig for FixtureConfig {
+    fn get_is_free_software(&self, app_id: &str, license: Option<&str>) -> Result<bool> {
+        let policy: LicensePolicy = self
+            .read_fixture("is-free-software.json")?
+            .unwrap_or_default();
+        Ok(policy.is_free_software(app_id, license))
+    }
+
+    fn has_license_backend(&self) -> bool {
+        true
+    }
+
+    fn get_build(&self) -> Result<BuildExtended> {
+        self.read_fixture("build.json")?
+            .ok_or_else(|| anyhow!("build.json is missing from {}", self.dir.display()))
+    }
+
+    fn linter_config(&self) -> &LinterConfig {
+        &self.linter
+    }
+
+    fn screenshot_mirror_url(&self) -> &str {
+        &self.screenshot_mirror_url
+    }
+
+    fn app_suffixes(&self) -> &[String] {
+        &self.app_suffixes
+    }
+
+    fn repo_path(&self) -> &Path {
+        &self.repo
+    }
+
+    fn skiplist(&self) -> Result<Vec<SkiplistEntry>> {
+        Ok(self.skiplist.clone())
+    }
+
+    fn exceptions(&self) -> Result<Exceptions> {
+        load_exceptions(self.exceptions_file.as_deref())
+    }
+}
+
+impl Config for FixtureConfig {
+    fn get_build_id(&self) -> Result<i64> {
+        Ok(self.build_id)
+    }
+
+    fn get_job_id(&self) -> Result<i64> {
+        Ok(self.job_id)
+    }
+
+    fn get_is_republish(&self) -> Result<bool> {
+        Ok(self.is_republish)
+    }
+
+    fn validation_observe_only(&self) -> bool {
+        self.validation_observe_only
+    }
+
+    fn production_repo(&self) -> Option<&Path> {
+        self.production_repo.as_deref()
+    }
+
+    fn get_storefront_info(&self, app_id: &str) -> Result<StorefrontInfo> {
+        Ok(self
+            .read_fixture(&format!("storefront-info/{app_id}.json"))?
+            .unwrap_or_default())
+    }
+
+    fn set_check_status(&self, args: &ReviewRequestArgs) -> Result<()> {
+        self.write_output("check-status.json", args)
+    }
+
+    fn post_review_request(&self, request: ReviewRequest) -> Result<ReviewRequestResponse> {
+        self.write_output("review-request.json", &request)?;
+        Ok(self
+            .read_fixture("review-response.json")?
+            .unwrap_or(ReviewRequestResponse {
+                requires_review: false,
+            }))
+    }
+
+    fn post_email_notification(&self, result: &CheckResult) -> Result<()> {
+        if result.diagnostics.is_empty() {
+            return Ok(());
+        }
+
+        let build = self.get_build()?;
+        let app_id = match build.build.app_id {
+            Some(app_id) => app_id,
+            None => return Ok(()),
+        };
+
+        self.write_output(
+            "email-notification.json",
+            &BuildNotificationRequest {
+                app_id,
+                build_id: self.build_id,
+                build_repo: build.build.repo,
+                diagnostics: &result.diagnostics,
+            },
+        )
+    }
+
+    fn post_publish_report(&self, report: &PublishReport) -> Result<()> {
+        self.write_output("publish-report.json", report)
+    }
 }
@@ -1,16 +1,25 @@
+mod cache;
+mod cmd_mock_server;
 mod cmd_publish;
 mod cmd_review;
+mod cmd_rollback;
 mod cmd_validate;
 mod config;
 mod job_utils;
+mod provenance;
 mod review;
+mod skiplist;
+mod spdx;
 mod storefront;
+mod subprocess;
 mod utils;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use cmd_mock_server::MockServerArgs;
 use cmd_publish::PublishArgs;
 use cmd_review::ReviewArgs;
+use cmd_rollback::RollbackArgs;
 use cmd_validate::ValidateArgs;
 use std::env;
 
@@ -22,8 +31,10 @@ struct Args {
 
 #[derive(Subcommand, Debug)]
 enum Command {
+    MockServer(MockServerArgs),
     Publish(PublishArgs),
     Review(ReviewArgs),
+    Rollback(RollbackArgs),
     Validate(ValidateArgs),
 }
 
@@ -37,8 +48,10 @@ fn main() -> Result<()> {
     let args = Args::parse();
 
     match args.command {
+        Command::MockServer(cmd) => cmd.run(),
         Command::Publish(cmd) => cmd.run(),
         Command::Review(cmd) => cmd.run(),
+        Command::Rollback(cmd) => cmd.run(),
         Command::Validate(cmd) => cmd.run(),
     }
 }
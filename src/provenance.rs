@@ -0,0 +1,82 @@
+use anyhow::Result;
+use ostree::glib::{compute_checksum_for_data, ChecksumType, VariantDict};
+use serde::Serialize;
+
+use crate::storefront::StorefrontInfo;
+
+/// The commit metadata key where the publish hook records the checksum of the commit it rewrote.
+const ORIGINAL_COMMIT_KEY: &str = "flathub.original-commit";
+const HOOKS_VERSION_KEY: &str = "flathub.hooks-version";
+const TIMESTAMP_KEY: &str = "flathub.rewrite-timestamp";
+const BUILD_ID_KEY: &str = "flathub.build-id";
+const STOREFRONT_INFO_KEY: &str = "flathub.storefront-info-sha256";
+
+/// Records how the publish hook rewrote a commit. This is stored in the rewritten commit's metadata.
+#[derive(Debug, Serialize)]
+pub struct Provenance {
+    /// The checksum of the commit that was rewritten.
+    pub original_commit: String,
+    /// The version of flathub-hooks that rewrote it.
+    pub hooks_version: String,
+    /// When it was rewritten, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The build that was being published. On republishes, this is kept from the previous publish.
+    pub build_id: Option<i64>,
+    /// The SHA-256 of the storefront info that was applied, serialized as JSON.
+    pub storefront_info_sha256: String,
+}
+
+impl Provenance {
+    pub fn new(
+        original_commit: &str,
+        timestamp: u64,
+        build_id: Option<i64>,
+        storefront_info: &StorefrontInfo,
+    ) -> Result<Self> {
+        Ok(Self {
+            original_commit: original_commit.to_string(),
+            hooks_version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp,
+            build_id,
+            storefront_info_sha256: compute_checksum_for_data(
+                ChecksumType::Sha256,
+                serde_json::to_string(storefront_info)?.as_bytes(),
+            )
+            .unwrap()
+            .to_string(),
+        })
+    }
+
+    /// Writes the provenance to a commit's metadata. Numbers are stored big-endian, like OSTree's own commit timestamp.
+    pub fn record(&self, metadata: &VariantDict) {
+        metadata.insert(ORIGINAL_COMMIT_KEY, &self.original_commit);
+        metadata.insert(HOOKS_VERSION_KEY, &self.hooks_version);
+        metadata.insert(TIMESTAMP_KEY, self.timestamp.to_be());
+        if let Some(build_id) = self.build_id {
+            metadata.insert(BUILD_ID_KEY, build_id.to_be());
+        }
+        metadata.insert(STOREFRONT_INFO_KEY, &self.storefront_info_sha256);
+    }
+
+    /// Reads the provenance from a commit's metadata. Returns `None` if the commit wasn't rewritten by the publish hook.
+    pub fn load(metadata: &VariantDict) -> Option<Self> {
+        let string = |key| metadata.lookup::<String>(key).ok().flatten();
+
+        Some(Self {
+            original_commit: string(ORIGINAL_COMMIT_KEY)?,
+            hooks_version: string(HOOKS_VERSION_KEY).unwrap_or_default(),
+            timestamp: metadata
+                .lookup::<u64>(TIMESTAMP_KEY)
+                .ok()
+                .flatten()
+                .map(u64::from_be)
+                .unwrap_or_default(),
+            build_id: metadata
+                .lookup::<i64>(BUILD_ID_KEY)
+                .ok()
+                .flatten()
+                .map(i64::from_be),
+            storefront_info_sha256: string(STOREFRONT_INFO_KEY).unwrap_or_default(),
+        })
+    }
+}
@@ -0,0 +1,244 @@
+use elementtree::Element;
+use reqwest::Url;
+
+use super::diagnostics::{DiagnosticInfo, ValidationDiagnostic};
+
+/// A check in the native appstream validator. The IDs are part of the report format (and can be referenced by
+/// exceptions), so they must not be changed once added.
+struct AppstreamRule {
+    id: &'static str,
+    is_warning: bool,
+}
+
+const NAME_MISSING: AppstreamRule = AppstreamRule {
+    id: "appstream-name-missing",
+    is_warning: false,
+};
+const SUMMARY_MISSING: AppstreamRule = AppstreamRule {
+    id: "appstream-summary-missing",
+    is_warning: false,
+};
+const METADATA_LICENSE_MISSING: AppstreamRule = AppstreamRule {
+    id: "appstream-metadata-license-missing",
+    is_warning: false,
+};
+const PROJECT_LICENSE_MISSING: AppstreamRule = AppstreamRule {
+    id: "appstream-project-license-missing",
+    is_warning: false,
+};
+const LAUNCHABLE_MISSING: AppstreamRule = AppstreamRule {
+    id: "appstream-launchable-missing",
+    is_warning: false,
+};
+const RELEASES_MISSING: AppstreamRule = AppstreamRule {
+    id: "appstream-releases-missing",
+    is_warning: true,
+};
+const CONTENT_RATING_MISSING: AppstreamRule = AppstreamRule {
+    id: "appstream-content-rating-missing",
+    is_warning: false,
+};
+const ICON_MISSING: AppstreamRule = AppstreamRule {
+    id: "appstream-icon-missing",
+    is_warning: false,
+};
+const SCREENSHOT_URL_INVALID: AppstreamRule = AppstreamRule {
+    id: "appstream-screenshot-url-invalid",
+    is_warning: false,
+};
+
+/// Checks an appstream catalog component against Flathub's rule set. This is used instead of `appstream-util
+/// validate` or `appstreamcli validate`, which sometimes produce false positives. `content` is the raw XML of the
+/// catalog file, which is used to find line numbers.
+pub fn validate_appstream_rules(
+    component: &Element,
+    content: &str,
+    refstring: &str,
+) -> Vec<ValidationDiagnostic> {
+    let mut diagnostics = vec![];
+    let mut push = |rule: &AppstreamRule, message: String, line: Option<u64>| {
+        let info = DiagnosticInfo::AppstreamRule {
+            rule_id: rule.id.to_string(),
+            message,
+            line,
+        };
+        diagnostics.push(if rule.is_warning {
+            ValidationDiagnostic::new_warning(info, Some(refstring.to_string()))
+        } else {
+            ValidationDiagnostic::new(info, Some(refstring.to_string()))
+        });
+    };
+
+    let has_text = |tag: &str| {
+        component
+            .find_all(tag)
+            .any(|el| !el.text().trim().is_empty())
+    };
+
+    for (rule, tag) in [
+        (&NAME_MISSING, "name"),
+        (&SUMMARY_MISSING, "summary"),
+        (&METADATA_LICENSE_MISSING, "metadata_license"),
+        (&PROJECT_LICENSE_MISSING, "project_license"),
+    ] {
+        if !has_text(tag) {
+            push(rule, format!("The component has no <{tag}>"), None);
+        }
+    }
+
+    /* Only graphical apps need a launchable. Console apps, addons, etc. don't have a desktop file. */
+    let component_type = component.get_attr("type").unwrap_or_default();
+    if matches!(component_type, "desktop" | "desktop-application") && !has_text("launchable") {
+        push(
+            &LAUNCHABLE_MISSING,
+            "The component has no <launchable>".to_string(),
+            None,
+        );
+    }
+
+    if !component
+        .find_all("releases")
+        .any(|releases| releases.find("release").is_some())
+    {
+        push(
+            &RELEASES_MISSING,
+            "The component has no <release> entries".to_string(),
+            None,
+        );
+    }
+
+    /* Addons and runtimes are shown as part of the app or runtime they extend, so they don't need an icon or content
+    rating of their own */
+    let is_addon_or_runtime = matches!(component_type, "addon" | "runtime");
+
+    if !is_addon_or_runtime && component.find("content_rating").is_none() {
+        push(
+            &CONTENT_RATING_MISSING,
+            "The component has no <content_rating>".to_string(),
+            None,
+        );
+    }
+
+    if !is_addon_or_runtime && !has_text("icon") {
+        push(
+            &ICON_MISSING,
+            "The component has no <icon>".to_string(),
+            None,
+        );
+    }
+
+    for url in component
+        .find_all("screenshots")
+        .flat_map(|screenshots| screenshots.find_all("screenshot"))
+        .flat_map(|screenshot| screenshot.find_all("image"))
+        .map(|image| image.text().trim())
+    {
+        let is_valid = Url::parse(url)
+            .map(|url| url.scheme() == "https" || url.scheme() == "http")
+            .unwrap_or(false);
+
+        if !is_valid {
+            push(
+                &SCREENSHOT_URL_INVALID,
+                format!("Screenshot URL is not a valid http(s) URL: {url}"),
+                find_line(content, url),
+            );
+        }
+    }
+
+    diagnostics
+}
+
+/// Finds the (1-based) number of the first line that contains the given text.
+fn find_line(content: &str, text: &str) -> Option<u64> {
+    if text.is_empty() {
+        return None;
+    }
+
+    content
+        .lines()
+        .position(|line| line.contains(text))
+        .map(|i| i as u64 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule_ids(diagnostics: &[ValidationDiagnostic]) -> Vec<&str> {
+        diagnostics
+            .iter()
+            .map(|d| match &d.info {
+                DiagnosticInfo::AppstreamRule { rule_id, .. } => rule_id.as_str(),
+                _ => panic!("unexpected diagnostic {d:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_component() {
+        let content = r#"<component type="desktop-application">
+    <id>org.flatpak.Test</id>
+    <name>Test</name>
+    <summary>A test app</summary>
+    <metadata_license>CC0-1.0</metadata_license>
+    <project_license>GPL-3.0-or-later</project_license>
+    <launchable type="desktop-id">org.flatpak.Test.desktop</launchable>
+    <icon type="cached" height="128" width="128">org.flatpak.Test.png</icon>
+    <content_rating type="oars-1.1"/>
+    <releases>
+        <release version="1.0" date="2024-01-01"/>
+    </releases>
+    <screenshots>
+        <screenshot type="default">
+            <image>https://dl.flathub.org/repo/screenshots/org.flatpak.Test-stable/orig/1.png</image>
+        </screenshot>
+    </screenshots>
+</component>"#;
+        let component = Element::from_reader(content.as_bytes()).unwrap();
+
+        let diagnostics =
+            validate_appstream_rules(&component, content, "app/org.flatpak.Test/x86_64/stable");
+
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    }
+
+    #[test]
+    fn test_invalid_component() {
+        let content = r#"<component type="desktop-application">
+    <id>org.flatpak.Test</id>
+    <name>Test</name>
+    <releases/>
+    <screenshots>
+        <screenshot type="default">
+            <image>file:///home/user/screenshot.png</image>
+        </screenshot>
+    </screenshots>
+</component>"#;
+        let component = Element::from_reader(content.as_bytes()).unwrap();
+
+        let diagnostics =
+            validate_appstream_rules(&component, content, "app/org.flatpak.Test/x86_64/stable");
+
+        assert_eq!(
+            rule_ids(&diagnostics),
+            vec![
+                SUMMARY_MISSING.id,
+                METADATA_LICENSE_MISSING.id,
+                PROJECT_LICENSE_MISSING.id,
+                LAUNCHABLE_MISSING.id,
+                RELEASES_MISSING.id,
+                CONTENT_RATING_MISSING.id,
+                ICON_MISSING.id,
+                SCREENSHOT_URL_INVALID.id,
+            ]
+        );
+        assert!(diagnostics[4].is_warning);
+        assert!(!diagnostics[0].is_warning);
+
+        match &diagnostics[7].info {
+            DiagnosticInfo::AppstreamRule { line, .. } => assert_eq!(*line, Some(7)),
+            _ => unreachable!(),
+        }
+    }
+}
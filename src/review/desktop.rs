@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use ostree::gio::Cancellable;
+use ostree::glib::{KeyFile, KeyFileFlags};
+use ostree::prelude::*;
+use ostree::Repo;
+
+use crate::utils::{
+    get_command_path, id_from_ref, list_files_recursive, load_metadata, read_repo_file,
+};
+
+use super::diagnostics::{DiagnosticInfo, ValidationDiagnostic};
+
+const DESKTOP_FILES_DIR: &str = "export/share/applications";
+const ICONS_DIR: &str = "export/share/icons";
+
+/// Main categories that are too generic to be useful (they describe the toolkit or desktop, not what the app does).
+/// These are the same ones flatpak-builder-lint rejects.
+const FORBIDDEN_CATEGORIES: [&str; 10] = [
+    "GTK",
+    "Qt",
+    "KDE",
+    "GNOME",
+    "Motif",
+    "Java",
+    "GUI",
+    "Application",
+    "XFCE",
+    "DDE",
+];
+
+/// Validates the desktop files an app exports to the user's system.
+pub fn validate_desktop_files(
+    repo: &Repo,
+    refstring: &str,
+    checksum: &str,
+) -> Result<Vec<ValidationDiagnostic>> {
+    let (root, _) = repo.read_commit(checksum, Cancellable::NONE)?;
+
+    let command = load_metadata(repo, checksum)
+        .ok()
+        .and_then(|(_, metadata)| metadata.string("Application", "command").ok())
+        .map(|command| command.to_string());
+
+    /* Icons are looked up by name, without the size directory or the extension */
+    let icons = list_files_recursive(&root.resolve_relative_path(ICONS_DIR), ICONS_DIR)?
+        .into_iter()
+        .filter_map(|(path, _)| {
+            let name = path.rsplit('/').next()?;
+            [".png", ".svg", ".svgz"]
+                .iter()
+                .find_map(|ext| name.strip_suffix(ext))
+                .map(str::to_string)
+        })
+        .collect::<HashSet<_>>();
+
+    let mut diagnostics = vec![];
+
+    for (path, file) in list_files_recursive(
+        &root.resolve_relative_path(DESKTOP_FILES_DIR),
+        DESKTOP_FILES_DIR,
+    )? {
+        if !path.ends_with(".desktop") {
+            continue;
+        }
+
+        let content =
+            String::from_utf8_lossy(&read_repo_file(file.downcast_ref().unwrap())?).into_owned();
+
+        diagnostics.extend(check_desktop_file(
+            &path,
+            &content,
+            &id_from_ref(refstring),
+            command.as_deref(),
+            &icons,
+            refstring,
+        ));
+    }
+
+    Ok(diagnostics)
+}
+
+/// Checks a single desktop file. `command` is the app's command from its `metadata`, if it has one, and `icons` is the
+/// names of the icons the app exports.
+fn check_desktop_file(
+    path: &str,
+    content: &str,
+    app_id: &str,
+    command: Option<&str>,
+    icons: &HashSet<String>,
+    refstring: &str,
+) -> Vec<ValidationDiagnostic> {
+    let mut diagnostics = vec![];
+    let mut push =
+        |info| diagnostics.push(ValidationDiagnostic::new(info, Some(refstring.to_string())));
+
+    /* Flatpak only exports desktop files that are named after the app ID (e.g. `org.example.App.desktop` or
+    `org.example.App.Editor.desktop`) */
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    let stem = file_name.strip_suffix(".desktop").unwrap_or(file_name);
+    if stem != app_id && !stem.starts_with(&format!("{app_id}.")) {
+        push(DiagnosticInfo::DesktopFileWrongName {
+            path: path.to_string(),
+            app_id: app_id.to_string(),
+        });
+    }
+
+    let keyfile = KeyFile::new();
+    if let Err(e) = keyfile.load_from_data(content, KeyFileFlags::NONE) {
+        push(DiagnosticInfo::DesktopFileInvalid {
+            path: path.to_string(),
+            error: e.to_string(),
+        });
+        return diagnostics;
+    }
+
+    let get = |key: &str| {
+        keyfile
+            .string("Desktop Entry", key)
+            .ok()
+            .map(|value| value.to_string())
+    };
+
+    for key in ["Name", "Type"] {
+        if get(key).is_none() {
+            push(DiagnosticInfo::DesktopFileMissingKey {
+                path: path.to_string(),
+                key: key.to_string(),
+            });
+        }
+    }
+
+    /* Flatpak rewrites Exec to use `flatpak run` when the app is installed, but it keeps the arguments, so the program
+    has to be the app's command (or already be `flatpak run`) */
+    if let (Some(exec), Some(command)) = (get("Exec"), command) {
+        let args = exec
+            .split_whitespace()
+            .map(|arg| arg.trim_matches('"'))
+            .collect::<Vec<_>>();
+
+        let is_valid = match args[..] {
+            ["flatpak", ref rest @ ..] => rest.contains(&"run"),
+            [program, ..] => get_command_path(program) == get_command_path(command),
+            [] => false,
+        };
+
+        if !is_valid {
+            push(DiagnosticInfo::DesktopFileInvalidExec {
+                path: path.to_string(),
+                exec,
+                command: command.to_string(),
+            });
+        }
+    }
+
+    if let Some(icon) = get("Icon") {
+        if !icons.contains(&icon) {
+            push(DiagnosticInfo::DesktopFileMissingIcon {
+                path: path.to_string(),
+                icon,
+            });
+        }
+    }
+
+    for category in get("Categories")
+        .unwrap_or_default()
+        .split(';')
+        .filter(|category| FORBIDDEN_CATEGORIES.contains(category))
+    {
+        push(DiagnosticInfo::DesktopFileForbiddenCategory {
+            path: path.to_string(),
+            category: category.to_string(),
+        });
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "export/share/applications/org.flatpak.Test.desktop";
+    const REFSTRING: &str = "app/org.flatpak.Test/x86_64/stable";
+
+    fn check(path: &str, content: &str) -> Vec<DiagnosticInfo> {
+        let icons = HashSet::from(["org.flatpak.Test".to_string()]);
+        check_desktop_file(
+            path,
+            content,
+            "org.flatpak.Test",
+            Some("test-app"),
+            &icons,
+            REFSTRING,
+        )
+        .into_iter()
+        .map(|d| d.info)
+        .collect()
+    }
+
+    #[test]
+    fn test_valid_desktop_file() {
+        let diagnostics = check(
+            PATH,
+            "[Desktop Entry]\nName=Test\nType=Application\nExec=test-app %U\nIcon=org.flatpak.Test\nCategories=Utility;\n",
+        );
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+
+        let diagnostics = check(
+            "export/share/applications/org.flatpak.Test.Editor.desktop",
+            "[Desktop Entry]\nName=Test Editor\nType=Application\nExec=/app/bin/test-app --editor\nIcon=org.flatpak.Test\n",
+        );
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    }
+
+    #[test]
+    fn test_invalid_desktop_file() {
+        let diagnostics = check(
+            "export/share/applications/test-app.desktop",
+            "[Desktop Entry]\nType=Application\nExec=other-app\nIcon=test-app\nCategories=Qt;Utility;\n",
+        );
+
+        assert!(matches!(
+            &diagnostics[..],
+            [
+                DiagnosticInfo::DesktopFileWrongName { .. },
+                DiagnosticInfo::DesktopFileMissingKey { key, .. },
+                DiagnosticInfo::DesktopFileInvalidExec { exec, .. },
+                DiagnosticInfo::DesktopFileMissingIcon { icon, .. },
+                DiagnosticInfo::DesktopFileForbiddenCategory { category, .. },
+            ] if key == "Name" && exec == "other-app" && icon == "test-app" && category == "Qt"
+        ));
+    }
+
+    #[test]
+    fn test_unparseable_desktop_file() {
+        let diagnostics = check(PATH, "Name=Test\n");
+        assert!(matches!(
+            &diagnostics[..],
+            [DiagnosticInfo::DesktopFileInvalid { .. }]
+        ));
+    }
+}
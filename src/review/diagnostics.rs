@@ -1,5 +1,7 @@
 use serde::Serialize;
 
+use crate::skiplist::SkippableCheck;
+
 #[derive(Debug, Serialize)]
 pub struct CheckResult {
     pub diagnostics: Vec<ValidationDiagnostic>,
@@ -9,6 +11,9 @@ pub struct CheckResult {
 pub struct ValidationDiagnostic {
     pub refstring: Option<String>,
     pub is_warning: bool,
+    /// Whether the finding matches an entry in the exceptions list. Waived findings are still reported, but don't fail
+    /// the build.
+    pub waived: bool,
     #[serde(flatten)]
     pub info: DiagnosticInfo,
 }
@@ -18,13 +23,122 @@ pub struct ValidationDiagnostic {
 pub enum DiagnosticInfo {
     /// The appstream file is missing or couldn't be read.
     FailedToLoadAppstream { path: String, error: String },
-    /// There is a problem in one of the appstream files.
+    /// flatpak-builder-lint failed, but its output couldn't be parsed into individual findings.
     FlatpakBuilderLint {
         stdout: serde_json::value::Value,
         stderr: String,
     },
+    /// flatpak-builder-lint reported a problem. `level` is the array the finding was listed in (`error`, `warning` or
+    /// `info`).
+    FlatpakBuilderLintFinding { level: String, finding: String },
+    /// The ref's appstream data describes it as an addon, but its `metadata` has no `[ExtensionOf]` group, so Flatpak
+    /// won't treat it as an extension.
+    MissingExtensionOf,
+    /// The appstream data of an extension is not an `addon` component that extends the app or runtime from the
+    /// `[ExtensionOf]` group of its `metadata`.
+    ExtensionAppstreamMismatch {
+        expected_extends: String,
+        error: String,
+    },
+    /// A check was skipped because the app is in the skiplist. This is informational, so exemptions can be audited.
+    CheckSkipped {
+        check: SkippableCheck,
+        reason: String,
+        expires: Option<String>,
+    },
+    /// An external tool didn't finish within its timeout and was killed, so its checks couldn't be completed.
+    ToolTimedOut { tool: String, seconds: u64 },
     /// The app is FOSS, but a URL for the build's CI log was not given or is not a valid URL.
     MissingBuildLogUrl,
+    /// The appstream file has screenshots, but the build does not contain a screenshots branch for the ref's
+    /// architecture. This usually means `--mirror-screenshots-url` was not passed to flatpak-builder or the
+    /// screenshots were never committed.
+    NoScreenshotBranch,
+    /// A screenshot URL in the appstream file does not point to a file in the screenshots branch.
+    MissingScreenshot { url: String, branch: String },
+    /// An executable or library was built for a different architecture than the ref it is in.
+    WrongArchExecutable {
+        path: String,
+        expected_arch: String,
+        detected_arch: String,
+        detected_arch_code: u16,
+    },
+    /// The `metadata` file is missing or couldn't be parsed.
+    FailedToLoadMetadata { error: String },
+    /// The `name` in the `metadata` file does not match the ID in the refstring.
+    MetadataNameMismatch {
+        expected: String,
+        found: Option<String>,
+    },
+    /// A runtime or SDK in the `metadata` file is missing or is not a valid `ID/ARCH/BRANCH` ref.
+    MalformedMetadataRef { key: String, value: Option<String> },
+    /// The `command` in the `metadata` file does not exist in the build.
+    MissingCommand { command: String, path: String },
+    /// The `metadata` file does not match the copy in the commit's `xa.metadata` key, which is what Flatpak reads
+    /// before installing the app.
+    CommitMetadataMismatch { has_xa_metadata: bool },
+    /// An exported desktop file is not named after the app ID, so Flatpak won't export it.
+    DesktopFileWrongName { path: String, app_id: String },
+    /// An exported desktop file couldn't be parsed.
+    DesktopFileInvalid { path: String, error: String },
+    /// An exported desktop file is missing a required key in its `[Desktop Entry]` group.
+    DesktopFileMissingKey { path: String, key: String },
+    /// The `Exec` line of an exported desktop file doesn't run the app's command.
+    DesktopFileInvalidExec {
+        path: String,
+        exec: String,
+        command: String,
+    },
+    /// The `Icon` of an exported desktop file is not one of the icons exported by the app.
+    DesktopFileMissingIcon { path: String, icon: String },
+    /// An exported desktop file uses a category that is too generic, such as the toolkit the app is written in.
+    DesktopFileForbiddenCategory { path: String, category: String },
+    /// An exported icon is not a valid PNG or SVG file.
+    IconInvalid { path: String, error: String },
+    /// An exported PNG icon's dimensions don't match the size directory it is in.
+    IconWrongSize {
+        path: String,
+        size_dir: String,
+        width: u32,
+        height: u32,
+    },
+    /// The app doesn't export an icon named after its ID that is at least `min_size` pixels or scalable.
+    MissingLargeIcon { min_size: u32 },
+    /// An `<icon type="cached">` in the appstream catalog file doesn't exist in the cached icons directory.
+    MissingCachedIcon { path: String },
+    /// The `project_license` in the appstream file is not a valid SPDX license expression.
+    InvalidLicenseExpression { license: String, error: String },
+    /// The `project_license` in the appstream file uses license or exception identifiers that aren't on the SPDX
+    /// license list (or at least not the part of it we know about).
+    UnknownLicenseIds { license: String, ids: Vec<String> },
+    /// Whether the app is free software according to its `project_license` doesn't match the backend's answer.
+    LicenseClassificationMismatch {
+        license: String,
+        local_is_free_software: bool,
+        backend_is_free_software: bool,
+    },
+    /// The appstream catalog file failed one of the native appstream checks. `rule_id` is stable and identifies the
+    /// check; `line` is only set when the problem can be traced to a specific line.
+    AppstreamRule {
+        rule_id: String,
+        message: String,
+        line: Option<u64>,
+    },
+}
+
+impl DiagnosticInfo {
+    /// The ID used to refer to this kind of finding in the exceptions list. This is the rule ID for native appstream
+    /// checks, the finding itself for flatpak-builder-lint findings, and the category for everything else.
+    pub fn rule_id(&self) -> String {
+        match self {
+            DiagnosticInfo::AppstreamRule { rule_id, .. } => rule_id.clone(),
+            DiagnosticInfo::FlatpakBuilderLintFinding { finding, .. } => finding.clone(),
+            _ => serde_json::to_value(self)
+                .ok()
+                .and_then(|value| value["category"].as_str().map(str::to_string))
+                .unwrap_or_default(),
+        }
+    }
 }
 
 impl ValidationDiagnostic {
@@ -32,10 +146,25 @@ impl ValidationDiagnostic {
         Self {
             refstring,
             is_warning: false,
+            waived: false,
+            info,
+        }
+    }
+
+    pub fn new_warning(info: DiagnosticInfo, refstring: Option<String>) -> Self {
+        Self {
+            refstring,
+            is_warning: true,
+            waived: false,
             info,
         }
     }
 
+    /// Whether the finding should fail the build.
+    pub fn is_failure(&self) -> bool {
+        !self.is_warning && !self.waived
+    }
+
     pub fn new_failed_to_load_appstream(path: &str, error: &str, refstring: &str) -> Self {
         Self::new(
             DiagnosticInfo::FailedToLoadAppstream {
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use crate::utils::app_id_from_ref;
+
+use super::diagnostics::ValidationDiagnostic;
+
+/// Findings that have been waived for specific apps. Maps app IDs to rule IDs to the reason for the exception, in the
+/// same format as flatpak-builder-lint's `exceptions.json`.
+pub type Exceptions = HashMap<String, HashMap<String, String>>;
+
+/// Marks every diagnostic that has an exception for its app and rule ID as waived. Waived diagnostics are kept in the
+/// results but don't fail the build. Diagnostics that aren't tied to a ref (such as some of the linter's) count as
+/// being about the build's app, `build_app_id`.
+pub fn apply_exceptions(
+    exceptions: &Exceptions,
+    build_app_id: Option<&str>,
+    app_suffixes: &[String],
+    diagnostics: &mut [ValidationDiagnostic],
+) {
+    for diagnostic in diagnostics.iter_mut() {
+        let app_id = match (&diagnostic.refstring, build_app_id) {
+            (Some(refstring), _) => app_id_from_ref(refstring, app_suffixes),
+            (None, Some(app_id)) => app_id.to_string(),
+            (None, None) => continue,
+        };
+
+        if exceptions
+            .get(&app_id)
+            .is_some_and(|rules| rules.contains_key(&diagnostic.info.rule_id()))
+        {
+            diagnostic.waived = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{review::diagnostics::DiagnosticInfo, utils::default_app_suffixes};
+
+    use super::*;
+
+    #[test]
+    fn test_apply_exceptions() {
+        let exceptions: Exceptions = serde_json::from_str(
+            r#"{
+                "org.flatpak.Test": {
+                    "missing_build_log_url": "Built on the developer's own infrastructure",
+                    "appstream-icon-missing": "The app is a command line tool"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let mut diagnostics = vec![
+            ValidationDiagnostic::new(
+                DiagnosticInfo::MissingBuildLogUrl,
+                Some("app/org.flatpak.Test/x86_64/stable".to_string()),
+            ),
+            ValidationDiagnostic::new(
+                DiagnosticInfo::AppstreamRule {
+                    rule_id: "appstream-icon-missing".to_string(),
+                    message: "The component has no <icon>".to_string(),
+                    line: None,
+                },
+                Some("app/org.flatpak.Test/aarch64/stable".to_string()),
+            ),
+            ValidationDiagnostic::new(
+                DiagnosticInfo::AppstreamRule {
+                    rule_id: "appstream-name-missing".to_string(),
+                    message: "The component has no <name>".to_string(),
+                    line: None,
+                },
+                Some("app/org.flatpak.Test/x86_64/stable".to_string()),
+            ),
+            ValidationDiagnostic::new(
+                DiagnosticInfo::MissingBuildLogUrl,
+                Some("app/org.flatpak.Other/x86_64/stable".to_string()),
+            ),
+            ValidationDiagnostic::new(
+                DiagnosticInfo::FlatpakBuilderLintFinding {
+                    level: "warning".to_string(),
+                    finding: "appstream-icon-missing".to_string(),
+                },
+                None,
+            ),
+        ];
+
+        let suffixes = default_app_suffixes();
+        apply_exceptions(
+            &exceptions,
+            Some("org.flatpak.Test"),
+            &suffixes,
+            &mut diagnostics,
+        );
+
+        assert_eq!(
+            diagnostics.iter().map(|d| d.waived).collect::<Vec<_>>(),
+            vec![true, true, false, false, true]
+        );
+
+        diagnostics[4].waived = false;
+        apply_exceptions(&exceptions, None, &suffixes, &mut diagnostics[4..]);
+        assert!(!diagnostics[4].waived);
+    }
+}
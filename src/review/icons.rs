@@ -0,0 +1,285 @@
+use anyhow::Result;
+use elementtree::Element;
+use flate2::read::GzDecoder;
+use ostree::gio::Cancellable;
+use ostree::prelude::*;
+use ostree::Repo;
+
+use crate::utils::{app_id_from_ref, list_files_recursive, load_appstream, read_repo_file};
+
+use super::diagnostics::{DiagnosticInfo, ValidationDiagnostic};
+
+const ICONS_DIR: &str = "export/share/icons/hicolor";
+const CACHED_ICONS_DIR: &str = "files/share/app-info/icons/flatpak";
+const DESKTOP_FILES_DIR: &str = "export/share/applications";
+
+/// The smallest icon size that looks good on the website and in software centers.
+const MIN_ICON_SIZE: u32 = 128;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Validates the icons an app exports to the user's system, and the icons cached for its appstream data.
+pub fn validate_icons(
+    repo: &Repo,
+    refstring: &str,
+    checksum: &str,
+    app_suffixes: &[String],
+) -> Result<Vec<ValidationDiagnostic>> {
+    let (root, _) = repo.read_commit(checksum, Cancellable::NONE)?;
+    let app_id = app_id_from_ref(refstring, app_suffixes);
+
+    let mut diagnostics = vec![];
+    let mut push =
+        |info| diagnostics.push(ValidationDiagnostic::new(info, Some(refstring.to_string())));
+
+    let mut has_large_icon = false;
+
+    for (path, file) in list_files_recursive(&root.resolve_relative_path(ICONS_DIR), ICONS_DIR)? {
+        /* Only look at app icons, i.e. `hicolor/{size}/apps/{name}` */
+        let (size_dir, name) = match path
+            .strip_prefix(ICONS_DIR)
+            .unwrap_or_default()
+            .split('/')
+            .collect::<Vec<_>>()[..]
+        {
+            ["", size_dir, "apps", name] => (size_dir.to_string(), name.to_string()),
+            _ => continue,
+        };
+
+        let content = read_repo_file(file.downcast_ref().unwrap())?;
+
+        if name.ends_with(".png") {
+            let (width, height) = match png_dimensions(&content) {
+                Some(dimensions) => dimensions,
+                None => {
+                    push(DiagnosticInfo::IconInvalid {
+                        path,
+                        error: "Not a valid PNG file".to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            match icon_dir_size(&size_dir) {
+                Some(size) if width == size && height == size => {
+                    has_large_icon |= size >= MIN_ICON_SIZE && is_main_icon(&name, &app_id);
+                }
+                _ => push(DiagnosticInfo::IconWrongSize {
+                    path,
+                    size_dir,
+                    width,
+                    height,
+                }),
+            }
+        } else if name.ends_with(".svg") || name.ends_with(".svgz") {
+            if let Err(error) = check_svg(&content, name.ends_with(".svgz")) {
+                push(DiagnosticInfo::IconInvalid { path, error });
+                continue;
+            }
+
+            has_large_icon |= size_dir == "scalable" && is_main_icon(&name, &app_id);
+        }
+    }
+
+    let appstream = load_appstream(repo, &app_id, checksum)
+        .ok()
+        .map(|(_, appstream)| appstream);
+
+    /* Console apps don't show up in app launchers, so they don't need a large icon */
+    let exports_desktop_file = list_files_recursive(
+        &root.resolve_relative_path(DESKTOP_FILES_DIR),
+        DESKTOP_FILES_DIR,
+    )?
+    .iter()
+    .any(|(path, _)| path.ends_with(".desktop"));
+    if !has_large_icon
+        && (exports_desktop_file || appstream.as_ref().is_some_and(is_desktop_application))
+    {
+        push(DiagnosticInfo::MissingLargeIcon {
+            min_size: MIN_ICON_SIZE,
+        });
+    }
+
+    /* Software centers show the icons that were cached when the appstream data was composed, so make sure they're
+    there. If the appstream file can't be loaded, that's reported by the appstream checks. */
+    if let Some(appstream) = &appstream {
+        for path in cached_icon_paths(appstream) {
+            if !root
+                .resolve_relative_path(&path)
+                .query_exists(Cancellable::NONE)
+            {
+                push(DiagnosticInfo::MissingCachedIcon { path });
+            }
+        }
+    }
+
+    Ok(diagnostics)
+}
+
+/// Whether the appstream component is a graphical app (`desktop-application`, or the older `desktop`).
+fn is_desktop_application(appstream: &Element) -> bool {
+    appstream.find_all("component").any(|component| {
+        matches!(
+            component.get_attr("type"),
+            Some("desktop-application" | "desktop")
+        )
+    })
+}
+
+/// Whether an icon is the app's main icon (as opposed to e.g. a symbolic icon or an icon for another desktop file).
+fn is_main_icon(name: &str, app_id: &str) -> bool {
+    name.rsplit_once('.')
+        .is_some_and(|(stem, _extension)| stem == app_id)
+}
+
+/// Gets the size in pixels of the icons in an icon theme size directory, e.g. 128 for `128x128` or `64x64@2`.
+fn icon_dir_size(size_dir: &str) -> Option<u32> {
+    let (size, scale) = match size_dir.split_once('@') {
+        Some((size, scale)) => (size, scale.parse::<u32>().ok()?),
+        None => (size_dir, 1),
+    };
+
+    let (width, height) = size.split_once('x')?;
+    if width != height {
+        return None;
+    }
+
+    width.parse::<u32>().ok().map(|size| size * scale)
+}
+
+/// Reads the width and height from a PNG file's IHDR chunk, which always comes first.
+fn png_dimensions(content: &[u8]) -> Option<(u32, u32)> {
+    if content.len() < 24 || !content.starts_with(PNG_SIGNATURE) || &content[12..16] != b"IHDR" {
+        return None;
+    }
+
+    let read_u32 =
+        |offset: usize| u32::from_be_bytes(content[offset..offset + 4].try_into().unwrap());
+    Some((read_u32(16), read_u32(20)))
+}
+
+/// Makes sure an SVG file is XML with an `<svg>` root element.
+fn check_svg(content: &[u8], is_compressed: bool) -> Result<(), String> {
+    let root = if is_compressed {
+        Element::from_reader(GzDecoder::new(content))
+    } else {
+        Element::from_reader(content)
+    }
+    .map_err(|e| e.to_string())?;
+
+    if root.tag().name() != "svg" {
+        return Err(format!("Expected <svg>, not <{}>", root.tag().name()));
+    }
+
+    Ok(())
+}
+
+/// Lists the paths where the `<icon type="cached">` entries of an appstream catalog file should be. Icons without a
+/// size are 64x64, like in appstream-glib.
+fn cached_icon_paths(appstream: &Element) -> Vec<String> {
+    appstream
+        .find_all("component")
+        .flat_map(|component| component.find_all("icon"))
+        .filter(|icon| icon.get_attr("type") == Some("cached"))
+        .filter(|icon| !icon.text().trim().is_empty())
+        .map(|icon| {
+            let width = icon.get_attr("width").unwrap_or("64");
+            let height = icon.get_attr("height").unwrap_or("64");
+            let scale = match icon.get_attr("scale") {
+                Some(scale) if scale != "1" => format!("@{scale}"),
+                _ => String::new(),
+            };
+            format!(
+                "{CACHED_ICONS_DIR}/{width}x{height}{scale}/{}",
+                icon.text().trim()
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_desktop_application() {
+        let appstream = |component_type: &str| {
+            let xml = format!(
+                r#"<components><component type="{component_type}"><id>org.flatpak.Test</id></component></components>"#
+            );
+            Element::from_reader(xml.as_bytes()).unwrap()
+        };
+
+        assert!(is_desktop_application(&appstream("desktop-application")));
+        assert!(is_desktop_application(&appstream("desktop")));
+        assert!(!is_desktop_application(&appstream("console-application")));
+    }
+
+    #[test]
+    fn test_icon_dir_size() {
+        assert_eq!(icon_dir_size("128x128"), Some(128));
+        assert_eq!(icon_dir_size("64x64@2"), Some(128));
+        assert_eq!(icon_dir_size("scalable"), None);
+        assert_eq!(icon_dir_size("64x32"), None);
+    }
+
+    #[test]
+    fn test_png_dimensions() {
+        let mut png = PNG_SIGNATURE.to_vec();
+        png.extend_from_slice(&13u32.to_be_bytes());
+        png.extend_from_slice(b"IHDR");
+        png.extend_from_slice(&256u32.to_be_bytes());
+        png.extend_from_slice(&128u32.to_be_bytes());
+
+        assert_eq!(png_dimensions(&png), Some((256, 128)));
+        assert_eq!(png_dimensions(&png[..20]), None);
+        assert_eq!(png_dimensions(b"<svg/>"), None);
+    }
+
+    #[test]
+    fn test_check_svg() {
+        assert!(check_svg(include_bytes!("../../tests/app_icon.svg"), false).is_ok());
+        assert!(check_svg(b"<html></html>", false).is_err());
+        assert!(check_svg(b"not xml", false).is_err());
+    }
+
+    #[test]
+    fn test_is_main_icon() {
+        assert!(is_main_icon("org.flatpak.Test.png", "org.flatpak.Test"));
+        assert!(is_main_icon("org.flatpak.Test.svg", "org.flatpak.Test"));
+        assert!(!is_main_icon(
+            "org.flatpak.Test-symbolic.svg",
+            "org.flatpak.Test"
+        ));
+        assert!(!is_main_icon(
+            "org.flatpak.Test.Editor.png",
+            "org.flatpak.Test"
+        ));
+    }
+
+    #[test]
+    fn test_cached_icon_paths() {
+        let appstream = Element::from_reader(
+            r#"<components>
+    <component type="desktop-application">
+        <id>org.flatpak.Test</id>
+        <icon type="stock">org.flatpak.Test</icon>
+        <icon type="cached" height="64" width="64">org.flatpak.Test.png</icon>
+        <icon type="cached" height="128" width="128">org.flatpak.Test.png</icon>
+        <icon type="cached" height="64" width="64" scale="2">org.flatpak.Test.png</icon>
+    </component>
+</components>"#
+                .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            cached_icon_paths(&appstream),
+            vec![
+                "files/share/app-info/icons/flatpak/64x64/org.flatpak.Test.png",
+                "files/share/app-info/icons/flatpak/128x128/org.flatpak.Test.png",
+                "files/share/app-info/icons/flatpak/64x64@2/org.flatpak.Test.png",
+            ]
+        );
+    }
+}
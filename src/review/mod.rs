@@ -2,24 +2,28 @@ use std::collections::HashMap;
 
 use anyhow::Result;
 use log::info;
-use ostree::gio::{Cancellable, File};
+use ostree::gio::Cancellable;
 use ostree::Repo;
 
 use crate::config::{Config, ValidateConfig};
 use crate::review::diagnostics::CheckResult;
 use crate::review::moderation::review_build;
 use crate::review::validation::validate_build;
+use crate::utils::open_repo;
 
+mod appstream;
+mod desktop;
 pub mod diagnostics;
+pub mod exceptions;
+mod icons;
 pub mod moderation;
+mod permissions;
 mod validation;
 
 pub fn do_validation<C: ValidateConfig>(
     config: &C,
 ) -> Result<(Repo, HashMap<String, String>, CheckResult)> {
-    /* Open the build repo at the current directory */
-    let repo = Repo::new(&File::for_path("."));
-    repo.open(Cancellable::NONE)?;
+    let repo = open_repo(config.repo_path())?;
 
     let refs = repo.list_refs(None, Cancellable::NONE)?;
 
@@ -37,19 +41,19 @@ pub fn do_validation<C: ValidateConfig>(
 }
 
 pub fn do_review<C: Config>(config: &C) -> Result<()> {
-    let (_, _, result) = do_validation(config)?;
+    let (repo, refs, result) = do_validation(config)?;
 
     /* If any errors were found, mark the check as failed */
-    if result.diagnostics.iter().any(|d| !d.is_warning) {
+    if result.diagnostics.iter().any(|d| d.is_failure()) {
         config.mark_failure("One or more validations failed.", &result)?;
         config.post_email_notification(&result)?;
         return Ok(());
     }
 
-    let request = review_build(config)?;
+    let request = review_build(config, &repo, &refs)?;
 
     /* Make sure nothing failed while collecting metadata for the moderation step */
-    if result.diagnostics.iter().any(|d| !d.is_warning) {
+    if result.diagnostics.iter().any(|d| d.is_failure()) {
         config.mark_failure("One or more validations failed.", &result)?;
         config.post_email_notification(&result)?;
         return Ok(());
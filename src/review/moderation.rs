@@ -1,20 +1,85 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
+use elementtree::Element;
+use ostree::Repo;
 use serde::{Deserialize, Serialize};
 
-use crate::config::Config;
+use crate::{
+    config::Config,
+    utils::{app_id_from_ref, is_primary_ref, load_appstream, open_repo},
+};
+
+use super::permissions::{diff_permissions, PermissionDiff};
 
 /// Review the metadata for a build and create a review request to send to the backend.
-pub fn review_build<C: Config>(config: &C) -> Result<ReviewRequest> {
+pub fn review_build<C: Config>(
+    config: &C,
+    repo: &Repo,
+    refs: &HashMap<String, String>,
+) -> Result<ReviewRequest> {
+    /* If we have access to the production repo, compare the build's permissions with the ones that are currently
+    published */
+    let production_repo = config.production_repo().map(open_repo).transpose()?;
+
     /* Collect the app's metadata and send it to the backend, to see if it needs to be held for review */
     let request = ReviewRequest {
         build_id: config.get_build_id()?,
         job_id: config.get_job_id()?,
+        app_metadata: collect_review_items(
+            repo,
+            refs,
+            production_repo.as_ref(),
+            config.app_suffixes(),
+        )?,
+        permissions: diff_permissions(repo, refs, production_repo.as_ref(), config.app_suffixes())?,
     };
 
     Ok(request)
 }
 
-#[derive(Debug, Serialize)]
+/// Collects the reviewable appstream metadata for each app in the build, along with the previously published values
+/// if the production repo is available.
+fn collect_review_items(
+    repo: &Repo,
+    refs: &HashMap<String, String>,
+    production_repo: Option<&Repo>,
+    app_suffixes: &[String],
+) -> Result<HashMap<String, ReviewItemChange>> {
+    let mut items = HashMap::new();
+
+    /* Sort the refs so that the same arch is picked for each app every time. The appstream data should be the same
+    for every arch anyway. */
+    let mut refs: Vec<_> = refs.iter().collect();
+    refs.sort();
+
+    for (refstring, checksum) in refs {
+        let app_id = app_id_from_ref(refstring, app_suffixes);
+
+        if !is_primary_ref(refstring, app_suffixes) || items.contains_key(&app_id) {
+            continue;
+        }
+
+        /* If the appstream file is missing or invalid, the validation step will already have reported it */
+        let new = match ReviewItem::load(repo, &app_id, checksum) {
+            Some(item) => item,
+            None => continue,
+        };
+
+        let old = match production_repo {
+            Some(production_repo) => production_repo
+                .resolve_rev(refstring, true)?
+                .and_then(|old_checksum| ReviewItem::load(production_repo, &app_id, &old_checksum)),
+            None => None,
+        };
+
+        items.insert(app_id, ReviewItemChange { old, new });
+    }
+
+    Ok(items)
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Serialize)]
 pub struct ReviewItem {
     name: Option<String>,
     summary: Option<String>,
@@ -24,13 +89,94 @@ pub struct ReviewItem {
     compulsory_for_desktop: Option<String>,
 }
 
+impl ReviewItem {
+    /// Loads the review item from the appstream catalog file in the given commit. Returns `None` if the file is missing
+    /// or doesn't contain exactly one component.
+    fn load(repo: &Repo, app_id: &str, checksum: &str) -> Option<Self> {
+        let (_, appstream) = load_appstream(repo, app_id, checksum).ok()?;
+        match appstream.find_all("component").collect::<Vec<_>>()[..] {
+            [component] => Some(Self::from_component(component)),
+            _ => None,
+        }
+    }
+
+    fn from_component(component: &Element) -> Self {
+        /* Only use the untranslated values */
+        let text = |parent: &Element, tag: &str| {
+            parent
+                .find_all(tag)
+                .find(|el| !el.attrs().any(|(name, _)| name.name() == "lang"))
+                .map(|el| el.text().trim().to_string())
+        };
+
+        Self {
+            name: text(component, "name"),
+            summary: text(component, "summary"),
+            developer_name: text(component, "developer_name").or_else(|| {
+                component
+                    .find("developer")
+                    .and_then(|developer| text(developer, "name"))
+            }),
+            project_license: text(component, "project_license"),
+            project_group: text(component, "project_group"),
+            compulsory_for_desktop: text(component, "compulsory_for_desktop"),
+        }
+    }
+}
+
+/// The reviewable metadata of an app in the new build, and in the currently published build if there is one.
+#[derive(Debug, Serialize)]
+pub struct ReviewItemChange {
+    pub old: Option<ReviewItem>,
+    pub new: ReviewItem,
+}
+
 #[derive(Debug, Serialize)]
 pub struct ReviewRequest {
     pub build_id: i64,
     pub job_id: i64,
+    /// Appstream metadata for each app in the build, keyed by app ID.
+    pub app_metadata: HashMap<String, ReviewItemChange>,
+    /// Permission changes for each primary ref, keyed by refstring.
+    pub permissions: HashMap<String, PermissionDiff>,
 }
 
 #[derive(Deserialize)]
 pub struct ReviewRequestResponse {
     pub requires_review: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_review_item_from_component() {
+        let component = Element::from_reader(
+            r#"<component>
+    <id>org.flatpak.Test</id>
+    <name>Test</name>
+    <name xml:lang="de">Prüfung</name>
+    <summary>A test app</summary>
+    <developer>
+        <name>Flathub</name>
+    </developer>
+    <project_license>GPL-3.0-or-later</project_license>
+</component>"#
+                .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            ReviewItem::from_component(&component),
+            ReviewItem {
+                name: Some("Test".to_string()),
+                summary: Some("A test app".to_string()),
+                developer_name: Some("Flathub".to_string()),
+                project_license: Some("GPL-3.0-or-later".to_string()),
+                project_group: None,
+                compulsory_for_desktop: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use log::warn;
+use ostree::{glib::KeyFile, Repo};
+use serde::Serialize;
+
+use crate::utils::{is_primary_ref, load_metadata};
+
+/// The sections of the `metadata` file that grant the app permissions. These are the ones set by `finish-args`.
+const PERMISSION_SECTIONS: [&str; 4] = [
+    "Context",
+    "Session Bus Policy",
+    "System Bus Policy",
+    "Environment",
+];
+
+/// A single permission from an app's `metadata` file. List-valued keys in `[Context]` (e.g.
+/// `filesystems=home;xdg-run/pipewire-0;`) are split into one permission per item.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Permission {
+    pub section: String,
+    pub key: String,
+    pub value: String,
+}
+
+/// The permissions that were added or removed by a build, compared to the build that is currently published.
+#[derive(Debug, Default, Serialize)]
+pub struct PermissionDiff {
+    /// Whether the ref is not in the production repo yet. If so, all of its permissions are listed as added.
+    pub is_new_ref: bool,
+    pub added: Vec<Permission>,
+    pub removed: Vec<Permission>,
+}
+
+impl PermissionDiff {
+    pub fn new(old: Option<&KeyFile>, new: &KeyFile) -> Self {
+        let old_permissions = old.map(list_permissions).unwrap_or_default();
+        let new_permissions = list_permissions(new);
+
+        Self {
+            is_new_ref: old.is_none(),
+            added: new_permissions
+                .iter()
+                .filter(|p| !old_permissions.contains(p))
+                .cloned()
+                .collect(),
+            removed: old_permissions
+                .iter()
+                .filter(|p| !new_permissions.contains(p))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Lists all the permissions granted by a `metadata` file.
+fn list_permissions(metadata: &KeyFile) -> Vec<Permission> {
+    let mut permissions = vec![];
+
+    for section in PERMISSION_SECTIONS {
+        let keys = match metadata.keys(section) {
+            Ok(keys) => keys,
+            Err(_) => continue,
+        };
+
+        for key in keys.iter() {
+            let value = match metadata.string(section, key.as_str()) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+
+            let values = if section == "Context" {
+                value
+                    .split(';')
+                    .filter(|x| !x.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            } else {
+                vec![value.to_string()]
+            };
+
+            permissions.extend(values.into_iter().map(|value| Permission {
+                section: section.to_string(),
+                key: key.to_string(),
+                value,
+            }));
+        }
+    }
+
+    permissions
+}
+
+/// Compares the permissions of each primary ref in the build with the same ref in the production repo. Refs whose
+/// metadata can't be read (e.g. because it is broken, which the validators report separately) are left out.
+pub fn diff_permissions(
+    repo: &Repo,
+    refs: &HashMap<String, String>,
+    production_repo: Option<&Repo>,
+    app_suffixes: &[String],
+) -> Result<HashMap<String, PermissionDiff>> {
+    let mut diffs = HashMap::new();
+
+    for (refstring, checksum) in refs.iter() {
+        if !is_primary_ref(refstring, app_suffixes) {
+            continue;
+        }
+
+        let new_metadata = match load_metadata(repo, checksum) {
+            Ok((_, metadata)) => metadata,
+            Err(e) => {
+                warn!("Not comparing permissions of {refstring}: {e}");
+                continue;
+            }
+        };
+
+        let old_metadata = match production_repo {
+            Some(production_repo) => match production_repo.resolve_rev(refstring, true)? {
+                Some(old_checksum) => match load_metadata(production_repo, &old_checksum) {
+                    Ok((_, metadata)) => Some(metadata),
+                    Err(e) => {
+                        warn!("Not comparing permissions of {refstring}: {e} (in the production repo)");
+                        continue;
+                    }
+                },
+                None => None,
+            },
+            None => None,
+        };
+
+        diffs.insert(
+            refstring.clone(),
+            PermissionDiff::new(old_metadata.as_ref(), &new_metadata),
+        );
+    }
+
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use ostree::glib::KeyFileFlags;
+
+    use super::*;
+
+    fn keyfile(data: &str) -> KeyFile {
+        let keyfile = KeyFile::new();
+        keyfile.load_from_data(data, KeyFileFlags::NONE).unwrap();
+        keyfile
+    }
+
+    fn permission(section: &str, key: &str, value: &str) -> Permission {
+        Permission {
+            section: section.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_diff_permissions() {
+        let old = keyfile(
+            "[Application]\nname=org.flatpak.Test\n\n[Context]\nshared=network;ipc;\nfilesystems=xdg-download;\n\n[Session Bus Policy]\norg.freedesktop.Notifications=talk\n",
+        );
+        let new = keyfile(
+            "[Application]\nname=org.flatpak.Test\n\n[Context]\nshared=network;ipc;\nfilesystems=home;\n\n[Session Bus Policy]\norg.freedesktop.Notifications=talk\norg.kde.StatusNotifierWatcher=own\n\n[Environment]\nFOO=bar\n",
+        );
+
+        let diff = PermissionDiff::new(Some(&old), &new);
+
+        assert!(!diff.is_new_ref);
+        assert_eq!(
+            diff.added,
+            vec![
+                permission("Context", "filesystems", "home"),
+                permission("Session Bus Policy", "org.kde.StatusNotifierWatcher", "own"),
+                permission("Environment", "FOO", "bar"),
+            ]
+        );
+        assert_eq!(
+            diff.removed,
+            vec![permission("Context", "filesystems", "xdg-download")]
+        );
+    }
+
+    #[test]
+    fn test_diff_permissions_new_ref() {
+        let new =
+            keyfile("[Application]\nname=org.flatpak.Test\n\n[Context]\nsockets=x11;wayland;\n");
+
+        let diff = PermissionDiff::new(None, &new);
+
+        assert!(diff.is_new_ref);
+        assert_eq!(
+            diff.added,
+            vec![
+                permission("Context", "sockets", "x11"),
+                permission("Context", "sockets", "wayland"),
+            ]
+        );
+        assert!(diff.removed.is_empty());
+    }
+}
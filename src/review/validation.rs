@@ -1,19 +1,41 @@
 use std::collections::HashMap;
+use std::path::Path;
 use std::process::Command;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use elementtree::Element;
-use ostree::gio::Cancellable;
+use elf::abi::{EI_NIDENT, EM_386, EM_AARCH64, EM_ARM, EM_X86_64};
+use elf::endian::AnyEndian;
+use elf::file::{parse_ident, FileHeader};
+use elf::to_str::e_machine_to_str;
+use log::info;
+use ostree::gio::{Cancellable, FileQueryInfoFlags, FileType};
+use ostree::prelude::FileExt;
 use ostree::Repo;
 use reqwest::Url;
+use serde::Deserialize;
 
-use crate::config::ValidateConfig;
+use crate::config::{LinterConfig, ValidateConfig};
+use crate::skiplist::{Skiplist, SkippableCheck};
+use crate::spdx::LicenseExpr;
+use crate::subprocess::{run_tool, ToolResult};
 use crate::{
     job_utils::BuildExtended,
-    utils::{app_id_from_ref, get_appstream_path, is_primary_ref, load_appstream},
+    utils::{
+        app_id_from_ref, arch_from_ref, get_appstream_path, get_command_path, id_from_ref,
+        is_primary_ref, is_valid_partial_ref, list_files_recursive, load_appstream,
+        load_commit_metadata, load_metadata, read_file_header, RefKind,
+    },
 };
 
-use super::diagnostics::{CheckResult, DiagnosticInfo, ValidationDiagnostic};
+use super::{
+    appstream::validate_appstream_rules,
+    desktop::validate_desktop_files,
+    diagnostics::{CheckResult, DiagnosticInfo, ValidationDiagnostic},
+    exceptions::apply_exceptions,
+    icons::validate_icons,
+};
 
 /// Run all of the validations on a build.
 pub fn validate_build<C: ValidateConfig>(
@@ -23,77 +45,309 @@ pub fn validate_build<C: ValidateConfig>(
     refs: &HashMap<String, String>,
     result: &mut CheckResult,
 ) -> Result<()> {
-    for (refstring, checksum) in refs.iter() {
-        if is_primary_ref(refstring) {
-            result.diagnostics.extend(validate_primary_ref(
-                config, build, repo, refstring, checksum,
-            )?);
+    let skiplist = Skiplist::new(config.skiplist()?);
+
+    /* Refs that weren't asked for are left out of all the checks, including the linter and the skiplist. The full
+    list is still passed to the per-ref checks, which look up other refs such as the screenshots branch. */
+    let validated_refs = refs
+        .iter()
+        .filter(|(refstring, _)| config.should_validate_ref(refstring))
+        .map(|(refstring, checksum)| (refstring.clone(), checksum.clone()))
+        .collect::<HashMap<_, _>>();
+
+    result.diagnostics.extend(validate_flatpak_build(
+        config.linter_config(),
+        config.repo_path(),
+        &skiplist,
+        build.build.app_id.as_deref(),
+        &validated_refs,
+        config.app_suffixes(),
+    )?);
+
+    for (refstring, checksum) in validated_refs.iter() {
+        if !is_primary_ref(refstring, config.app_suffixes()) {
+            continue;
         }
+
+        let kind = RefKind::load(repo, refstring, checksum, config.app_suffixes());
+        result.diagnostics.extend(validate_primary_ref(
+            config, build, repo, refs, &skiplist, kind, refstring, checksum,
+        )?);
     }
 
+    apply_exceptions(
+        &config.exceptions()?,
+        build.build.app_id.as_deref(),
+        config.app_suffixes(),
+        &mut result.diagnostics,
+    );
+
     Ok(())
 }
 
-/// Run all the validations specific to "primary" refs (app, runtime, or extension).
+/// Run all the validations specific to "primary" refs (app, runtime, or extension). Some of the checks depend on the
+/// kind of ref.
+#[allow(clippy::too_many_arguments)]
 pub fn validate_primary_ref<C: ValidateConfig>(
     config: &C,
     build: &BuildExtended,
     repo: &Repo,
+    refs: &HashMap<String, String>,
+    skiplist: &Skiplist,
+    kind: RefKind,
     refstring: &str,
     checksum: &str,
 ) -> Result<Vec<ValidationDiagnostic>> {
     let (_, _checksum) = repo.read_commit(checksum, Cancellable::NONE)?;
 
+    let id = id_from_ref(refstring);
     let mut diagnostics = vec![];
-    diagnostics.extend(validate_flatpak_build(refstring)?);
+
+    if let Some(skipped) =
+        skiplist.skipped_diagnostic(&id, SkippableCheck::ExecutableArch, refstring)
+    {
+        diagnostics.push(skipped);
+    } else if matches!(kind, RefKind::AppExtension | RefKind::RuntimeExtension)
+        && is_i386_compat_extension(&id)
+    {
+        info!(
+            "Not checking executable architectures of {refstring}, it is an i386 compat extension"
+        );
+    } else {
+        diagnostics.extend(validate_executable_arch(repo, refstring, checksum)?);
+    }
+
+    if let Some(skipped) = skiplist.skipped_diagnostic(&id, SkippableCheck::Metadata, refstring) {
+        diagnostics.push(skipped);
+    } else {
+        diagnostics.extend(validate_metadata(repo, kind, refstring, checksum)?);
+    }
+
+    if kind == RefKind::App {
+        if let Some(skipped) =
+            skiplist.skipped_diagnostic(&id, SkippableCheck::DesktopFile, refstring)
+        {
+            diagnostics.push(skipped);
+        } else {
+            diagnostics.extend(validate_desktop_files(repo, refstring, checksum)?);
+        }
+
+        if let Some(skipped) = skiplist.skipped_diagnostic(&id, SkippableCheck::Icons, refstring) {
+            diagnostics.push(skipped);
+        } else {
+            diagnostics.extend(validate_icons(
+                repo,
+                refstring,
+                checksum,
+                config.app_suffixes(),
+            )?);
+        }
+    }
 
     /* Validate the appstream catalog file. This is the one that shows up on the website and in software centers.
     (The other ones are exported to the user's system.) */
-    diagnostics.extend(validate_appstream_catalog_file(
-        config, build, repo, checksum, refstring,
-    )?);
+    if let Some(skipped) = skiplist.skipped_diagnostic(&id, SkippableCheck::Appstream, refstring) {
+        diagnostics.push(skipped);
+    } else {
+        diagnostics.extend(validate_appstream_catalog_file(
+            config, build, repo, refs, kind, checksum, refstring,
+        )?);
+    }
 
     Ok(diagnostics)
 }
 
-fn run_flatpak_builder_lint(refstring: &str) -> Result<Vec<ValidationDiagnostic>> {
-    let output = Command::new("flatpak")
-        .args([
-            "run",
-            "--command=flatpak-builder-lint",
-            "org.flatpak.Builder",
-            "--exceptions",
-            "repo",
-            "--cwd",
-            "noop",
-        ])
-        .output()?;
-
-    if !output.status.success() {
-        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-
-        let stdout_json = match serde_json::from_str::<serde_json::Value>(&stdout) {
-            Ok(json) => json,
-            Err(_) => serde_json::Value::String(stdout),
-        };
+/// Builds the flatpak-builder-lint command line. The linter is told to check its working directory, which is set to
+/// the repo being validated.
+fn flatpak_builder_lint_command(linter: &LinterConfig, repo_path: &Path) -> Result<Command> {
+    let mut command_parts = linter.command.split_whitespace();
+    let program = command_parts
+        .next()
+        .ok_or(anyhow!("The linter command is empty"))?;
 
-        Ok(vec![ValidationDiagnostic::new(
-            DiagnosticInfo::FlatpakBuilderLint {
-                stdout: stdout_json,
-                stderr,
-            },
-            Some(refstring.to_string()),
-        )])
-    } else {
-        Ok(vec![])
+    let mut command = Command::new(program);
+    command.args(command_parts);
+    if linter.exceptions {
+        command.arg("--exceptions");
+    }
+    command
+        .args(&linter.extra_args)
+        .args(["repo", "--cwd", "noop"])
+        .current_dir(repo_path);
+
+    Ok(command)
+}
+
+/// Runs flatpak-builder-lint on the repo. It checks the whole repo at once, so each finding is attached to the ref it
+/// is about if the output says which one that is, and to `refstring` otherwise.
+fn run_flatpak_builder_lint(
+    linter: &LinterConfig,
+    repo_path: &Path,
+    refs: &[&String],
+    refstring: Option<&str>,
+) -> Result<Vec<ValidationDiagnostic>> {
+    let command = flatpak_builder_lint_command(linter, repo_path)?;
+
+    let output = match run_tool(command, Duration::from_secs(linter.timeout))? {
+        ToolResult::Exited(output) => output,
+        ToolResult::TimedOut => {
+            return Ok(vec![ValidationDiagnostic::new(
+                DiagnosticInfo::ToolTimedOut {
+                    tool: "flatpak-builder-lint".to_string(),
+                    seconds: linter.timeout,
+                },
+                refstring.map(str::to_string),
+            )])
+        }
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+    /* The linter exits successfully if it only found warnings, so look at the output either way */
+    let diagnostics =
+        parse_flatpak_builder_lint_output(&stdout, refs, refstring).unwrap_or_default();
+    if !diagnostics.is_empty() || output.status.success() {
+        return Ok(diagnostics);
+    }
+
+    /* The linter failed but its output couldn't be parsed, so include it as-is so it still shows up in the report */
+    let stdout_json = match serde_json::from_str::<serde_json::Value>(&stdout) {
+        Ok(json) => json,
+        Err(_) => serde_json::Value::String(stdout),
+    };
+
+    Ok(vec![ValidationDiagnostic::new(
+        DiagnosticInfo::FlatpakBuilderLint {
+            stdout: stdout_json,
+            stderr,
+        },
+        refstring.map(str::to_string),
+    )])
+}
+
+/// The parts of flatpak-builder-lint's JSON output that we use.
+#[derive(Deserialize)]
+struct FlatpakBuilderLintOutput {
+    #[serde(default)]
+    errors: Vec<String>,
+    #[serde(default)]
+    warnings: Vec<String>,
+    #[serde(default)]
+    info: Vec<String>,
+    /// The ID of the app the linter found in the repo, if any.
+    #[serde(default)]
+    appid: Option<String>,
+}
+
+/// Finds the ref a linter finding is about. Findings about a specific ref, such as `ref-not-found: <ref>`, name it.
+/// Other findings are about the app in `appid`, if the linter reported one.
+fn lint_finding_ref(finding: &str, appid: Option<&str>, refs: &[&String]) -> Option<String> {
+    let mentioned = finding
+        .split(|c: char| c.is_whitespace() || matches!(c, ',' | '"' | '\'' | '(' | ')'))
+        .map(|word| word.trim_end_matches([':', '.']))
+        .find(|word| refs.iter().any(|refstring| refstring == word));
+    if let Some(refstring) = mentioned {
+        return Some(refstring.to_string());
+    }
+
+    let appid = appid?;
+    refs.iter()
+        .filter(|refstring| id_from_ref(refstring) == appid)
+        .min()
+        .map(|refstring| refstring.to_string())
+}
+
+/// Converts flatpak-builder-lint's output into one diagnostic per finding. Each finding is attached to the ref among
+/// `refs` that it is about, or to `refstring` if it isn't about any ref in particular. Returns `None` if the output is
+/// not in the expected format.
+fn parse_flatpak_builder_lint_output(
+    stdout: &str,
+    refs: &[&String],
+    refstring: Option<&str>,
+) -> Option<Vec<ValidationDiagnostic>> {
+    let output = serde_json::from_str::<FlatpakBuilderLintOutput>(stdout).ok()?;
+    let appid = output.appid.as_deref();
+
+    let diagnostics = [
+        ("error", false, output.errors),
+        ("warning", true, output.warnings),
+        ("info", true, output.info),
+    ]
+    .into_iter()
+    .flat_map(|(level, is_warning, findings)| {
+        findings
+            .into_iter()
+            .map(move |finding| (level, is_warning, finding))
+    })
+    .map(|(level, is_warning, finding)| ValidationDiagnostic {
+        refstring: lint_finding_ref(&finding, appid, refs)
+            .or_else(|| refstring.map(str::to_string)),
+        is_warning,
+        waived: false,
+        info: DiagnosticInfo::FlatpakBuilderLintFinding {
+            level: level.to_string(),
+            finding,
+        },
+    })
+    .collect();
+
+    Some(diagnostics)
+}
+
+/// Picks the ref that linter findings without a ref of their own are attached to: the build's app (or runtime) itself,
+/// i.e. the first primary ref whose ID is the build's app ID. Builds without an app ID only get one if they have a
+/// single primary ref.
+fn lint_findings_ref(app_id: Option<&str>, refs: &[&String]) -> Option<String> {
+    let mut candidates = refs
+        .iter()
+        .filter(|refstring| app_id.is_none_or(|app_id| id_from_ref(refstring) == app_id))
+        .collect::<Vec<_>>();
+    candidates.sort();
+
+    match candidates[..] {
+        [refstring] => Some(refstring.to_string()),
+        [refstring, ..] if app_id.is_some() => Some(refstring.to_string()),
+        _ => None,
     }
 }
 
-fn validate_flatpak_build(refstring: &str) -> Result<Vec<ValidationDiagnostic>> {
+/// Run the validations that look at the build as a whole rather than at a single ref.
+fn validate_flatpak_build(
+    linter: &LinterConfig,
+    repo_path: &Path,
+    skiplist: &Skiplist,
+    app_id: Option<&str>,
+    refs: &HashMap<String, String>,
+    app_suffixes: &[String],
+) -> Result<Vec<ValidationDiagnostic>> {
     let mut diagnostics = vec![];
 
-    diagnostics.extend(run_flatpak_builder_lint(refstring)?);
+    /* The linter checks the whole repo, so only skip it if every app in the build is exempt */
+    let mut primary_refs = vec![];
+    let mut skipped = vec![];
+    for refstring in refs.keys().filter(|r| is_primary_ref(r, app_suffixes)) {
+        match skiplist.skipped_diagnostic(
+            &id_from_ref(refstring),
+            SkippableCheck::Linter,
+            refstring,
+        ) {
+            Some(diagnostic) => skipped.push(diagnostic),
+            None => primary_refs.push(refstring),
+        }
+    }
+    diagnostics.extend(skipped);
+
+    if linter.skip_external_linters {
+        info!("Skipping flatpak-builder-lint because external linters are disabled");
+    } else if !primary_refs.is_empty() {
+        diagnostics.extend(run_flatpak_builder_lint(
+            linter,
+            repo_path,
+            &refs.keys().collect::<Vec<_>>(),
+            lint_findings_ref(app_id, &primary_refs).as_deref(),
+        )?);
+    }
 
     Ok(diagnostics)
 }
@@ -102,17 +356,31 @@ fn validate_appstream_catalog_file<C: ValidateConfig>(
     config: &C,
     build: &BuildExtended,
     repo: &Repo,
+    refs: &HashMap<String, String>,
+    kind: RefKind,
     checksum: &str,
     refstring: &str,
 ) -> Result<Vec<ValidationDiagnostic>> {
-    let app_id = app_id_from_ref(refstring);
+    let app_id = app_id_from_ref(refstring, config.app_suffixes());
 
     let mut diagnostics = vec![];
 
     let appstream_path = get_appstream_path(&app_id);
-    let (_appstream_content, appstream) = match load_appstream(repo, &app_id, checksum) {
+    let (appstream_content, appstream) = match load_appstream(repo, &app_id, checksum) {
         Ok(x) => x,
-        Err(_) => {
+        Err(e) => {
+            /* Apps need appstream data to show up on the website and in software centers, and extensions need an
+            addon component to show up alongside what they extend. Runtimes are allowed to go without. */
+            if matches!(
+                kind,
+                RefKind::App | RefKind::AppExtension | RefKind::RuntimeExtension
+            ) {
+                diagnostics.push(ValidationDiagnostic::new_failed_to_load_appstream(
+                    &appstream_path,
+                    &e.to_string(),
+                    refstring,
+                ));
+            }
             return Ok(diagnostics);
         }
     };
@@ -145,18 +413,56 @@ fn validate_appstream_catalog_file<C: ValidateConfig>(
 
     diagnostics.extend(validate_appstream_component(
         component,
+        &app_id,
         refstring,
         &appstream_path,
     )?);
 
-    /* For now, we don't run `appstream-util validate` or `appstreamcli validate` on this file, because it sometimes
-    produces false positives. */
+    diagnostics.extend(validate_screenshots(
+        repo,
+        config.screenshot_mirror_url(),
+        refs,
+        component,
+        refstring,
+    )?);
+
+    match kind {
+        RefKind::AppExtension | RefKind::RuntimeExtension => {
+            diagnostics.extend(validate_extension_component(
+                repo, component, refstring, checksum,
+            )?);
+        }
+        RefKind::Runtime if component.get_attr("type") == Some("addon") => {
+            /* The appstream data says it's an extension, but Flatpak won't treat it as one */
+            diagnostics.push(ValidationDiagnostic::new(
+                DiagnosticInfo::MissingExtensionOf,
+                Some(refstring.to_string()),
+            ));
+        }
+        _ => {}
+    }
+
+    /* We don't run `appstream-util validate` or `appstreamcli validate` on this file, because it sometimes produces
+    false positives. Instead, check the rules we care about natively. */
+    diagnostics.extend(validate_appstream_rules(
+        component,
+        &appstream_content,
+        refstring,
+    ));
 
     /* If the app is free software, it must have a link to the build log. The link is stored in flat-manager and will
     be inserted into appstream by the publish hook. */
     let license = component.find("project_license").map(|x| x.text());
     let is_free_software = config.get_is_free_software(&app_id, license)?;
 
+    if let Some(license) = license {
+        diagnostics.extend(validate_license(
+            license.trim(),
+            config.has_license_backend().then_some(is_free_software),
+            refstring,
+        ));
+    }
+
     if is_free_software {
         let build_url = build
             .build_refs
@@ -166,19 +472,113 @@ fn validate_appstream_catalog_file<C: ValidateConfig>(
             .or(build.build.build_log_url.as_ref());
 
         if build_url.is_none() || Url::parse(build_url.unwrap()).is_err() {
-            diagnostics.push(ValidationDiagnostic {
-                info: DiagnosticInfo::MissingBuildLogUrl,
-                refstring: Some(refstring.to_string()),
-                is_warning: false,
-            })
+            diagnostics.push(ValidationDiagnostic::new(
+                DiagnosticInfo::MissingBuildLogUrl,
+                Some(refstring.to_string()),
+            ))
         }
     }
 
     Ok(diagnostics)
 }
 
-/// Make sure an appstream component has the correct ID.
-fn check_appstream_component_id(component: &Element, refstring: &str) -> Result<(), String> {
+/// Parses the app's license as an SPDX expression. If `backend_is_free_software` is given, it is compared with the
+/// local classification of the license.
+fn validate_license(
+    license: &str,
+    backend_is_free_software: Option<bool>,
+    refstring: &str,
+) -> Vec<ValidationDiagnostic> {
+    let expr = match LicenseExpr::parse(license) {
+        Ok(expr) => expr,
+        Err(error) => {
+            return vec![ValidationDiagnostic::new_warning(
+                DiagnosticInfo::InvalidLicenseExpression {
+                    license: license.to_string(),
+                    error,
+                },
+                Some(refstring.to_string()),
+            )]
+        }
+    };
+
+    let mut diagnostics = vec![];
+
+    let ids = expr.unknown_ids();
+    if !ids.is_empty() {
+        diagnostics.push(ValidationDiagnostic::new_warning(
+            DiagnosticInfo::UnknownLicenseIds {
+                license: license.to_string(),
+                ids,
+            },
+            Some(refstring.to_string()),
+        ));
+    }
+
+    /* The backend knows about exceptions for specific apps, so it has the final say, but a disagreement is worth a
+    look */
+    let local_is_free_software = expr.is_free_software();
+    if let Some(backend_is_free_software) = backend_is_free_software {
+        if backend_is_free_software != local_is_free_software {
+            diagnostics.push(ValidationDiagnostic::new_warning(
+                DiagnosticInfo::LicenseClassificationMismatch {
+                    license: license.to_string(),
+                    local_is_free_software,
+                    backend_is_free_software,
+                },
+                Some(refstring.to_string()),
+            ));
+        }
+    }
+
+    diagnostics
+}
+
+/// Make sure the appstream data of an extension describes it as an addon to the app or runtime it extends.
+fn validate_extension_component(
+    repo: &Repo,
+    component: &Element,
+    refstring: &str,
+    checksum: &str,
+) -> Result<Vec<ValidationDiagnostic>> {
+    let extension_of = load_metadata(repo, checksum)?
+        .1
+        .string("ExtensionOf", "ref")?
+        .to_string();
+    let expected_extends = id_from_ref(&extension_of);
+
+    let error = if component.get_attr("type") != Some("addon") {
+        Some(format!(
+            "Expected an addon component, not {}",
+            component.get_attr("type").unwrap_or("generic")
+        ))
+    } else if !component.find_all("extends").any(|extends| {
+        extends.text() == expected_extends
+            || extends.text() == format!("{expected_extends}.desktop")
+    }) {
+        Some(format!(
+            "The addon component does not have <extends>{expected_extends}</extends>"
+        ))
+    } else {
+        None
+    };
+
+    Ok(error
+        .map(|error| {
+            ValidationDiagnostic::new(
+                DiagnosticInfo::ExtensionAppstreamMismatch {
+                    expected_extends,
+                    error,
+                },
+                Some(refstring.to_string()),
+            )
+        })
+        .into_iter()
+        .collect())
+}
+
+/// Make sure an appstream component has the correct ID, i.e. the app ID of its ref.
+fn check_appstream_component_id(component: &Element, expected_id: &str) -> Result<(), String> {
     match component.find_all("id").count() {
         1 => {}
         0 => return Err("Appstream component does not have an ID".to_owned()),
@@ -186,7 +586,6 @@ fn check_appstream_component_id(component: &Element, refstring: &str) -> Result<
     }
 
     let id = component.find("id").unwrap();
-    let expected_id = app_id_from_ref(refstring);
     if id.text() != expected_id && id.text() != format!("{expected_id}.desktop") {
         return Err(format!(
             "Appstream component ID ({}) does not match expected ID ({expected_id})",
@@ -198,12 +597,13 @@ fn check_appstream_component_id(component: &Element, refstring: &str) -> Result<
 
 fn validate_appstream_component(
     component: &Element,
+    app_id: &str,
     refstring: &str,
     appstream_path: &str,
 ) -> Result<Vec<ValidationDiagnostic>> {
     let mut diagnostics = vec![];
 
-    if let Err(e) = check_appstream_component_id(component, refstring) {
+    if let Err(e) = check_appstream_component_id(component, app_id) {
         diagnostics.push(ValidationDiagnostic::new_failed_to_load_appstream(
             appstream_path,
             &e,
@@ -213,3 +613,434 @@ fn validate_appstream_component(
 
     Ok(diagnostics)
 }
+
+/// Make sure the screenshots referenced in the appstream file were mirrored into the build's screenshots branch.
+/// Mirrored screenshot URLs are relative to `mirror_url` (flatpak-builder's `--mirror-screenshots-url`), and the files
+/// themselves are committed to the `screenshots/<arch>` branch.
+fn validate_screenshots(
+    repo: &Repo,
+    mirror_url: &str,
+    refs: &HashMap<String, String>,
+    component: &Element,
+    refstring: &str,
+) -> Result<Vec<ValidationDiagnostic>> {
+    let urls = component
+        .find_all("screenshots")
+        .flat_map(|screenshots| screenshots.find_all("screenshot"))
+        .flat_map(|screenshot| screenshot.find_all("image"))
+        .map(|image| image.text().trim().to_string())
+        .filter(|url| !url.is_empty())
+        .collect::<Vec<_>>();
+
+    if urls.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let screenshot_branch = format!("screenshots/{}", arch_from_ref(refstring));
+
+    let screenshot_checksum = match refs.get(&screenshot_branch) {
+        Some(checksum) => checksum,
+        None => {
+            return Ok(vec![ValidationDiagnostic::new(
+                DiagnosticInfo::NoScreenshotBranch,
+                Some(refstring.to_string()),
+            )])
+        }
+    };
+
+    let (screenshot_root, _) = repo.read_commit(screenshot_checksum, Cancellable::NONE)?;
+
+    let mut diagnostics = vec![];
+    let mirror_url = format!("{}/", mirror_url.trim_end_matches('/'));
+
+    for url in urls {
+        let exists = url
+            .strip_prefix(&mirror_url)
+            .map(|path| {
+                screenshot_root
+                    .resolve_relative_path(path)
+                    .query_exists(Cancellable::NONE)
+            })
+            .unwrap_or(false);
+
+        if !exists {
+            diagnostics.push(ValidationDiagnostic::new(
+                DiagnosticInfo::MissingScreenshot {
+                    url,
+                    branch: screenshot_branch.clone(),
+                },
+                Some(refstring.to_string()),
+            ));
+        }
+    }
+
+    Ok(diagnostics)
+}
+
+/// Validate the `metadata` keyfile at the root of the commit, which tells Flatpak how to run the app.
+fn validate_metadata(
+    repo: &Repo,
+    kind: RefKind,
+    refstring: &str,
+    checksum: &str,
+) -> Result<Vec<ValidationDiagnostic>> {
+    let mut diagnostics = vec![];
+    let mut push =
+        |info| diagnostics.push(ValidationDiagnostic::new(info, Some(refstring.to_string())));
+
+    let (content, metadata) = match load_metadata(repo, checksum) {
+        Ok(x) => x,
+        Err(e) => {
+            push(DiagnosticInfo::FailedToLoadMetadata {
+                error: e.to_string(),
+            });
+            return Ok(diagnostics);
+        }
+    };
+
+    let group = if kind == RefKind::App {
+        "Application"
+    } else {
+        "Runtime"
+    };
+
+    let expected_id = id_from_ref(refstring);
+    let name = metadata.string(group, "name").ok().map(|x| x.to_string());
+    if name.as_deref() != Some(expected_id.as_str()) {
+        push(DiagnosticInfo::MetadataNameMismatch {
+            expected: expected_id,
+            found: name,
+        });
+    }
+
+    for (key, required) in [("runtime", group == "Application"), ("sdk", false)] {
+        let value = metadata.string(group, key).ok().map(|x| x.to_string());
+        let is_valid = match &value {
+            Some(value) => is_valid_partial_ref(value),
+            None => !required,
+        };
+        if !is_valid {
+            push(DiagnosticInfo::MalformedMetadataRef {
+                key: key.to_string(),
+                value,
+            });
+        }
+    }
+
+    if let Ok(command) = metadata.string(group, "command") {
+        if let Some(path) = get_command_path(&command) {
+            let (root, _) = repo.read_commit(checksum, Cancellable::NONE)?;
+            if !matches!(
+                root.resolve_relative_path(&path)
+                    .query_file_type(FileQueryInfoFlags::NOFOLLOW_SYMLINKS, Cancellable::NONE),
+                FileType::Regular | FileType::SymbolicLink
+            ) {
+                push(DiagnosticInfo::MissingCommand {
+                    command: command.to_string(),
+                    path,
+                });
+            }
+        }
+    }
+
+    let xa_metadata = load_commit_metadata(repo, checksum)?
+        .lookup::<String>("xa.metadata")
+        .ok()
+        .flatten();
+    if xa_metadata.as_deref() != Some(content.as_str()) {
+        push(DiagnosticInfo::CommitMetadataMismatch {
+            has_xa_metadata: xa_metadata.is_some(),
+        });
+    }
+
+    Ok(diagnostics)
+}
+
+/// Maps a Flatpak architecture name to the ELF machine type of binaries built for it.
+fn elf_machine_for_arch(arch: &str) -> Option<u16> {
+    match arch {
+        "x86_64" => Some(EM_X86_64),
+        "aarch64" => Some(EM_AARCH64),
+        "i386" => Some(EM_386),
+        "arm" => Some(EM_ARM),
+        _ => None,
+    }
+}
+
+/// Reads the machine type from an ELF header. Returns `None` if the data is not an ELF header.
+fn read_elf_machine(header: &[u8]) -> Option<u16> {
+    /* parse_ident() panics if the data is shorter than the ident */
+    if header.len() < EI_NIDENT {
+        return None;
+    }
+    let ident = parse_ident::<AnyEndian>(header).ok()?;
+    let file_header = FileHeader::parse_tail(ident, header.get(EI_NIDENT..)?).ok()?;
+    Some(file_header.e_machine)
+}
+
+/// Whether an extension exists to run 32-bit x86 programs on x86_64, such as `org.freedesktop.Platform.Compat.i386`
+/// or `org.freedesktop.Platform.GL32.default`. These ship i386 binaries in their x86_64 refs on purpose.
+fn is_i386_compat_extension(id: &str) -> bool {
+    let parts = id.split('.').collect::<Vec<_>>();
+    parts.ends_with(&["Compat", "i386"]) || parts.contains(&"GL32")
+}
+
+/// Make sure the executables and libraries in a commit were built for the ref's architecture. Broken CI setups
+/// sometimes copy binaries from another architecture's build.
+fn validate_executable_arch(
+    repo: &Repo,
+    refstring: &str,
+    checksum: &str,
+) -> Result<Vec<ValidationDiagnostic>> {
+    let expected_machine = match elf_machine_for_arch(&arch_from_ref(refstring)) {
+        Some(machine) => machine,
+        None => return Ok(vec![]),
+    };
+
+    let (root, _) = repo.read_commit(checksum, Cancellable::NONE)?;
+
+    let mut files = vec![];
+    for dir in ["files/bin", "files/lib"] {
+        files.extend(list_files_recursive(&root.resolve_relative_path(dir), dir)?);
+    }
+
+    /* The command doesn't have to be in bin/, e.g. it might be in libexec/ */
+    let command_path = load_metadata(repo, checksum)
+        .ok()
+        .and_then(|(_, metadata)| metadata.string("Application", "command").ok())
+        .and_then(|command| get_command_path(&command));
+    if let Some(command_path) = command_path {
+        let command_file = root.resolve_relative_path(&command_path);
+        if !files.iter().any(|(path, _)| *path == command_path)
+            && command_file
+                .query_file_type(FileQueryInfoFlags::NOFOLLOW_SYMLINKS, Cancellable::NONE)
+                == FileType::Regular
+        {
+            files.push((command_path, command_file));
+        }
+    }
+
+    let mut diagnostics = vec![];
+
+    for (path, file) in files {
+        let machine = match read_elf_machine(&read_file_header(&file, 64)?) {
+            Some(machine) => machine,
+            None => continue,
+        };
+
+        if machine != expected_machine {
+            diagnostics.push(ValidationDiagnostic::new_warning(
+                DiagnosticInfo::WrongArchExecutable {
+                    path,
+                    expected_arch: e_machine_to_str(expected_machine)
+                        .unwrap_or_default()
+                        .to_string(),
+                    detected_arch: e_machine_to_str(machine).unwrap_or("unknown").to_string(),
+                    detected_arch_code: machine,
+                },
+                Some(refstring.to_string()),
+            ));
+        }
+    }
+
+    Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_elf_machine() {
+        let mut header = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0];
+        header.resize(EI_NIDENT, 0);
+        header.extend_from_slice(&2_u16.to_le_bytes()); // e_type
+        header.extend_from_slice(&EM_AARCH64.to_le_bytes()); // e_machine
+        header.extend_from_slice(&1_u32.to_le_bytes()); // e_version
+        header.resize(64, 0);
+
+        assert_eq!(read_elf_machine(&header), Some(EM_AARCH64));
+        assert_eq!(read_elf_machine(b"#!/bin/sh\necho hello\n"), None);
+        assert_eq!(read_elf_machine(&header[..8]), None);
+    }
+
+    #[test]
+    fn test_is_i386_compat_extension() {
+        assert!(is_i386_compat_extension(
+            "org.freedesktop.Platform.Compat.i386"
+        ));
+        assert!(is_i386_compat_extension(
+            "org.freedesktop.Platform.GL32.default"
+        ));
+        assert!(!is_i386_compat_extension(
+            "org.freedesktop.Platform.GL.default"
+        ));
+        assert!(!is_i386_compat_extension(
+            "org.freedesktop.Platform.Compat.i386.Debug"
+        ));
+        assert!(!is_i386_compat_extension("com.example.GL32Viewer"));
+    }
+
+    #[test]
+    fn test_parse_flatpak_builder_lint_output() {
+        let app_ref = "app/org.flatpak.Test/x86_64/stable".to_string();
+        let locale_ref = "runtime/org.flatpak.Test.Locale/x86_64/stable".to_string();
+        let other_ref = "app/org.flatpak.Other/x86_64/stable".to_string();
+        let refs = vec![&locale_ref, &app_ref, &other_ref];
+
+        /* The build has no app ID and two apps, so only the linter's output says which ref a finding is about */
+        let diagnostics = parse_flatpak_builder_lint_output(
+            r#"{
+                "errors": ["finish-args-arbitrary-dbus-access"],
+                "warnings": ["appstream-missing-screenshots"],
+                "info": ["ref-not-found: runtime/org.flatpak.Test.Locale/x86_64/stable"],
+                "appid": "org.flatpak.Test",
+                "message": "Please consult the documentation"
+            }"#,
+            &refs,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(diagnostics.len(), 3);
+
+        assert!(!diagnostics[0].is_warning);
+        assert_eq!(diagnostics[0].refstring.as_ref(), Some(&app_ref));
+        assert!(matches!(
+            &diagnostics[0].info,
+            DiagnosticInfo::FlatpakBuilderLintFinding { level, finding }
+                if level == "error" && finding == "finish-args-arbitrary-dbus-access"
+        ));
+
+        assert!(diagnostics[1].is_warning);
+        assert_eq!(diagnostics[1].refstring.as_ref(), Some(&app_ref));
+
+        assert!(diagnostics[2].is_warning);
+        assert_eq!(diagnostics[2].refstring.as_ref(), Some(&locale_ref));
+
+        /* Findings that aren't about any ref in particular go to the fallback ref */
+        let diagnostics = parse_flatpak_builder_lint_output(
+            r#"{"errors": ["appstream-failed-validation"]}"#,
+            &refs,
+            Some(&other_ref),
+        )
+        .unwrap();
+        assert_eq!(diagnostics[0].refstring.as_ref(), Some(&other_ref));
+        let diagnostics = parse_flatpak_builder_lint_output(
+            r#"{"errors": ["appstream-failed-validation"], "appid": "org.flatpak.Missing"}"#,
+            &refs,
+            None,
+        )
+        .unwrap();
+        assert_eq!(diagnostics[0].refstring, None);
+
+        assert!(parse_flatpak_builder_lint_output(
+            "Traceback (most recent call last):",
+            &refs,
+            None
+        )
+        .is_none());
+        assert!(parse_flatpak_builder_lint_output("", &refs, None).is_none());
+        assert!(parse_flatpak_builder_lint_output("{}", &refs, None)
+            .unwrap()
+            .is_empty());
+
+        let primary_refs = vec![&app_ref, &other_ref];
+        assert_eq!(
+            lint_findings_ref(Some("org.flatpak.Test"), &primary_refs).as_ref(),
+            Some(&app_ref)
+        );
+        assert_eq!(
+            lint_findings_ref(None, &primary_refs[..1]).as_ref(),
+            Some(&app_ref)
+        );
+        assert_eq!(lint_findings_ref(None, &primary_refs), None);
+    }
+
+    #[test]
+    fn test_flatpak_builder_lint_command() {
+        let linter = LinterConfig {
+            command: "flatpak run --command=flatpak-builder-lint org.flatpak.Builder".to_string(),
+            extra_args: vec![
+                "--user-exceptions".to_string(),
+                "exceptions.json".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        /* The repo is somewhere other than the current directory, so that's where the linter must run */
+        let repo_path = std::env::temp_dir().join("flathub-hooks-test-repo");
+        let command = flatpak_builder_lint_command(&linter, &repo_path).unwrap();
+
+        assert_eq!(command.get_program(), "flatpak");
+        assert_eq!(command.get_current_dir(), Some(repo_path.as_path()));
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            [
+                "run",
+                "--command=flatpak-builder-lint",
+                "org.flatpak.Builder",
+                "--exceptions",
+                "--user-exceptions",
+                "exceptions.json",
+                "repo",
+                "--cwd",
+                "noop"
+            ]
+        );
+
+        let empty = LinterConfig {
+            command: " ".to_string(),
+            ..Default::default()
+        };
+        assert!(flatpak_builder_lint_command(&empty, &repo_path).is_err());
+    }
+
+    #[test]
+    fn test_validate_license() {
+        let refstring = "app/org.flatpak.Test/x86_64/stable";
+
+        assert!(validate_license("GPL-3.0-or-later OR MIT", Some(true), refstring).is_empty());
+        assert!(validate_license("LicenseRef-proprietary", Some(false), refstring).is_empty());
+        assert!(validate_license(
+            "LicenseRef-proprietary=https://example.com/eula.html",
+            Some(false),
+            refstring
+        )
+        .is_empty());
+
+        let diagnostics = validate_license("GPL-3", None, refstring);
+        assert!(matches!(
+            &diagnostics[..],
+            [ValidationDiagnostic {
+                is_warning: true,
+                info: DiagnosticInfo::UnknownLicenseIds { ids, .. },
+                ..
+            }] if ids == &vec!["GPL-3".to_string()]
+        ));
+
+        let diagnostics = validate_license("GPL-3.0-or-later,", Some(true), refstring);
+        assert!(matches!(
+            &diagnostics[..],
+            [ValidationDiagnostic {
+                is_warning: true,
+                info: DiagnosticInfo::InvalidLicenseExpression { .. },
+                ..
+            }]
+        ));
+
+        let diagnostics = validate_license("LicenseRef-proprietary", Some(true), refstring);
+        assert!(matches!(
+            &diagnostics[..],
+            [ValidationDiagnostic {
+                is_warning: true,
+                info: DiagnosticInfo::LicenseClassificationMismatch {
+                    local_is_free_software: false,
+                    backend_is_free_software: true,
+                    ..
+                },
+                ..
+            }]
+        ));
+    }
+}
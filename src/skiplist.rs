@@ -0,0 +1,230 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::review::diagnostics::{DiagnosticInfo, ValidationDiagnostic};
+
+/// The validations that an app can be exempted from.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkippableCheck {
+    /// flatpak-builder-lint
+    Linter,
+    /// The appstream catalog file checks, including screenshots and the build log URL
+    Appstream,
+    /// The architecture of executables and libraries
+    ExecutableArch,
+    /// The `metadata` keyfile
+    Metadata,
+    /// The exported desktop files
+    DesktopFile,
+    /// The exported icons and the cached appstream icons
+    Icons,
+}
+
+/// An exemption from some or all of the validations for an app.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SkiplistEntry {
+    pub app_id: String,
+    /// Why the app is exempted. This is included in the report so exemptions can be audited.
+    pub reason: String,
+    /// The last day (`YYYY-MM-DD`, UTC) the exemption applies.
+    #[serde(default)]
+    pub expires: Option<String>,
+    /// The checks to skip. If empty, all checks are skipped.
+    #[serde(default)]
+    pub checks: Vec<SkippableCheck>,
+}
+
+impl SkiplistEntry {
+    /// Whether the exemption has expired. The expiry date must already have been checked to be in `YYYY-MM-DD` format.
+    fn is_expired(&self, today: &str) -> bool {
+        /* Dates in YYYY-MM-DD format can be compared as strings */
+        self.expires
+            .as_deref()
+            .is_some_and(|expires| expires < today)
+    }
+
+    fn applies_to(&self, check: SkippableCheck) -> bool {
+        self.checks.is_empty() || self.checks.contains(&check)
+    }
+}
+
+/// Apps that were exempted from validation before the skiplist was configurable. Used when the config doesn't provide
+/// a skiplist.
+pub fn default_skiplist() -> Vec<SkiplistEntry> {
+    [
+        "net.wz2100.wz2100",
+        "org.freedesktop.Platform.ClInfo",
+        "org.freedesktop.Platform.GlxInfo",
+        "org.freedesktop.Platform.VaInfo",
+        "org.freedesktop.Platform.VdpauInfo",
+        "org.freedesktop.Platform.VulkanInfo",
+        "org.mozilla.Thunderbird",
+        "org.mozilla.firefox",
+    ]
+    .into_iter()
+    .map(|app_id| SkiplistEntry {
+        app_id: app_id.to_string(),
+        reason: "Exempted before the skiplist was configurable".to_string(),
+        expires: None,
+        checks: vec![],
+    })
+    .collect()
+}
+
+pub struct Skiplist {
+    entries: Vec<SkiplistEntry>,
+    today: String,
+}
+
+impl Skiplist {
+    pub fn new(entries: Vec<SkiplistEntry>) -> Self {
+        Self::new_at(entries, today())
+    }
+
+    /// Creates a skiplist as of the given date. Entries with a malformed expiry date are dropped.
+    fn new_at(entries: Vec<SkiplistEntry>, today: String) -> Self {
+        let entries = entries
+            .into_iter()
+            .filter(|entry| match &entry.expires {
+                Some(expires) if parse_date(expires).is_none() => {
+                    warn!(
+                        "Ignoring skiplist entry for {} because its expiry date ({expires}) is not in YYYY-MM-DD format",
+                        entry.app_id
+                    );
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+
+        Self { entries, today }
+    }
+
+    /// Finds the entry that exempts the app from the given check, if there is one. Expired entries are ignored.
+    pub fn find(&self, app_id: &str, check: SkippableCheck) -> Option<&SkiplistEntry> {
+        self.entries.iter().find(|entry| {
+            entry.app_id == app_id && entry.applies_to(check) && !entry.is_expired(&self.today)
+        })
+    }
+
+    /// If the app is exempt from the given check, returns an informational diagnostic that says so.
+    pub fn skipped_diagnostic(
+        &self,
+        app_id: &str,
+        check: SkippableCheck,
+        refstring: &str,
+    ) -> Option<ValidationDiagnostic> {
+        self.find(app_id, check).map(|entry| {
+            ValidationDiagnostic::new_warning(
+                DiagnosticInfo::CheckSkipped {
+                    check,
+                    reason: entry.reason.clone(),
+                    expires: entry.expires.clone(),
+                },
+                Some(refstring.to_string()),
+            )
+        })
+    }
+}
+
+/// Parses a `YYYY-MM-DD` date.
+fn parse_date(date: &str) -> Option<(i64, u32, u32)> {
+    match date.split('-').collect::<Vec<_>>()[..] {
+        [year, month, day] if year.len() == 4 && month.len() == 2 && day.len() == 2 => {
+            let month: u32 = month.parse().ok()?;
+            let day: u32 = day.parse().ok()?;
+            if (1..=12).contains(&month) && (1..=31).contains(&day) {
+                Some((year.parse().ok()?, month, day))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Today's date (UTC) in `YYYY-MM-DD` format.
+fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86400)
+        .unwrap_or_default();
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Converts a number of days since 1970-01-01 to a (year, month, day) date. See
+/// <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(app_id: &str, expires: Option<&str>, checks: Vec<SkippableCheck>) -> SkiplistEntry {
+        SkiplistEntry {
+            app_id: app_id.to_string(),
+            reason: "test".to_string(),
+            expires: expires.map(str::to_string),
+            checks,
+        }
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(civil_from_days(20743), (2026, 10, 17));
+    }
+
+    #[test]
+    fn test_skiplist() {
+        let skiplist = Skiplist::new_at(
+            vec![
+                entry("org.flatpak.All", None, vec![]),
+                entry(
+                    "org.flatpak.Linter",
+                    Some("2024-06-30"),
+                    vec![SkippableCheck::Linter],
+                ),
+                entry("org.flatpak.Expired", Some("2024-05-31"), vec![]),
+                entry("org.flatpak.Malformed", Some("30/06/2024"), vec![]),
+            ],
+            "2024-06-01".to_string(),
+        );
+        assert_eq!(skiplist.entries.len(), 3);
+
+        assert!(skiplist
+            .find("org.flatpak.All", SkippableCheck::Appstream)
+            .is_some());
+        assert!(skiplist
+            .find("org.flatpak.Linter", SkippableCheck::Linter)
+            .is_some());
+        assert!(skiplist
+            .find("org.flatpak.Linter", SkippableCheck::Appstream)
+            .is_none());
+        assert!(skiplist
+            .find("org.flatpak.Expired", SkippableCheck::Linter)
+            .is_none());
+        assert!(skiplist
+            .find("org.flatpak.Malformed", SkippableCheck::Linter)
+            .is_none());
+        assert!(skiplist
+            .find("org.flatpak.Other", SkippableCheck::Linter)
+            .is_none());
+    }
+}
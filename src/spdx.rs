@@ -0,0 +1,425 @@
+/// Licenses from the SPDX license list, and whether each one is free software (OSI approved or FSF libre). This
+/// doesn't include every license on the list, only the ones that apps on Flathub commonly use, so an unknown
+/// identifier is not necessarily invalid.
+const LICENSES: &[(&str, bool)] = &[
+    ("0BSD", true),
+    ("AFL-3.0", true),
+    ("AGPL-3.0", true),
+    ("AGPL-3.0-only", true),
+    ("AGPL-3.0-or-later", true),
+    ("Apache-1.1", true),
+    ("Apache-2.0", true),
+    ("APSL-2.0", true),
+    ("Artistic-1.0", true),
+    ("Artistic-2.0", true),
+    ("BSD-1-Clause", true),
+    ("BSD-2-Clause", true),
+    ("BSD-2-Clause-Patent", true),
+    ("BSD-3-Clause", true),
+    ("BSD-3-Clause-Clear", true),
+    ("BSD-4-Clause", true),
+    ("BSL-1.0", true),
+    ("CC-BY-3.0", false),
+    ("CC-BY-4.0", true),
+    ("CC-BY-NC-3.0", false),
+    ("CC-BY-NC-4.0", false),
+    ("CC-BY-NC-ND-3.0", false),
+    ("CC-BY-NC-ND-4.0", false),
+    ("CC-BY-NC-SA-3.0", false),
+    ("CC-BY-NC-SA-4.0", false),
+    ("CC-BY-ND-3.0", false),
+    ("CC-BY-ND-4.0", false),
+    ("CC-BY-SA-3.0", false),
+    ("CC-BY-SA-4.0", true),
+    ("CC0-1.0", true),
+    ("CDDL-1.0", true),
+    ("CECILL-2.0", true),
+    ("CECILL-2.1", true),
+    ("CECILL-B", true),
+    ("CECILL-C", true),
+    ("ECL-2.0", true),
+    ("EFL-2.0", true),
+    ("EPL-1.0", true),
+    ("EPL-2.0", true),
+    ("EUPL-1.1", true),
+    ("EUPL-1.2", true),
+    ("FSFAP", true),
+    ("FTL", true),
+    ("GFDL-1.3", true),
+    ("GFDL-1.3-only", true),
+    ("GFDL-1.3-or-later", true),
+    ("GPL-1.0-or-later", true),
+    ("GPL-2.0", true),
+    ("GPL-2.0+", true),
+    ("GPL-2.0-only", true),
+    ("GPL-2.0-or-later", true),
+    ("GPL-3.0", true),
+    ("GPL-3.0+", true),
+    ("GPL-3.0-only", true),
+    ("GPL-3.0-or-later", true),
+    ("HPND", true),
+    ("IJG", true),
+    ("IPA", true),
+    ("ISC", true),
+    ("LGPL-2.0", true),
+    ("LGPL-2.0+", true),
+    ("LGPL-2.0-only", true),
+    ("LGPL-2.0-or-later", true),
+    ("LGPL-2.1", true),
+    ("LGPL-2.1+", true),
+    ("LGPL-2.1-only", true),
+    ("LGPL-2.1-or-later", true),
+    ("LGPL-3.0", true),
+    ("LGPL-3.0+", true),
+    ("LGPL-3.0-only", true),
+    ("LGPL-3.0-or-later", true),
+    ("LPPL-1.3c", true),
+    ("MIT", true),
+    ("MIT-0", true),
+    ("MPL-1.1", true),
+    ("MPL-2.0", true),
+    ("MPL-2.0-no-copyleft-exception", true),
+    ("MS-PL", true),
+    ("MS-RL", true),
+    ("NCSA", true),
+    ("ODbL-1.0", true),
+    ("OFL-1.0", true),
+    ("OFL-1.1", true),
+    ("OpenSSL", true),
+    ("OSL-3.0", true),
+    ("PHP-3.01", true),
+    ("PostgreSQL", true),
+    ("Python-2.0", true),
+    ("Ruby", true),
+    ("SGI-B-2.0", true),
+    ("Sleepycat", true),
+    ("Unicode-DFS-2016", true),
+    ("Unlicense", true),
+    ("UPL-1.0", true),
+    ("Vim", true),
+    ("W3C", true),
+    ("WTFPL", true),
+    ("X11", true),
+    ("XFree86-1.1", true),
+    ("Zlib", true),
+    ("ZPL-2.0", true),
+    ("ZPL-2.1", true),
+];
+
+/// Exceptions that can follow `WITH`. These only grant extra permissions, so they don't affect whether a license is
+/// free software.
+const EXCEPTIONS: &[&str] = &[
+    "389-exception",
+    "Autoconf-exception-3.0",
+    "Bison-exception-2.2",
+    "Classpath-exception-2.0",
+    "eCos-exception-2.0",
+    "Font-exception-2.0",
+    "GCC-exception-3.1",
+    "GPL-3.0-linking-exception",
+    "GPL-3.0-linking-source-exception",
+    "LGPL-3.0-linking-exception",
+    "Linux-syscall-note",
+    "LLVM-exception",
+    "OpenSSL-exception",
+    "Qt-GPL-exception-1.0",
+    "Qt-LGPL-exception-1.1",
+    "Swift-exception",
+    "Universal-FOSS-exception-1.0",
+    "WxWindows-exception-3.1",
+];
+
+/// A parsed SPDX license expression.
+#[derive(Debug, Eq, PartialEq)]
+pub enum LicenseExpr {
+    /// A license identifier or `LicenseRef-`. `or_later` is set if it was followed by `+`.
+    License {
+        id: String,
+        or_later: bool,
+    },
+    With {
+        license: Box<LicenseExpr>,
+        exception: String,
+    },
+    And(Box<LicenseExpr>, Box<LicenseExpr>),
+    Or(Box<LicenseExpr>, Box<LicenseExpr>),
+}
+
+impl LicenseExpr {
+    /// Parses an SPDX license expression, e.g. `GPL-3.0-or-later OR (MIT AND LicenseRef-proprietary)`. Operators can
+    /// be upper or lower case.
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let tokens = tokenize(expr);
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+
+        let result = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(result),
+            Some(token) => Err(format!("Unexpected '{token}'")),
+        }
+    }
+
+    /// Whether the license is free software. For `OR`, any of the options may be chosen, so only one of them has to be
+    /// free; for `AND`, all of them have to be. Unknown licenses and custom `LicenseRef-`s are not free software.
+    pub fn is_free_software(&self) -> bool {
+        match self {
+            LicenseExpr::License { id, .. } => LICENSES
+                .iter()
+                .any(|(license, is_free)| *is_free && license == id),
+            LicenseExpr::With { license, .. } => license.is_free_software(),
+            LicenseExpr::And(a, b) => a.is_free_software() && b.is_free_software(),
+            LicenseExpr::Or(a, b) => a.is_free_software() || b.is_free_software(),
+        }
+    }
+
+    /// Lists the license and exception identifiers in the expression that are not in our copy of the SPDX list.
+    /// `LicenseRef-` and `DocumentRef-` references are custom, so they are never unknown.
+    pub fn unknown_ids(&self) -> Vec<String> {
+        match self {
+            LicenseExpr::License { id, .. } => {
+                if id.starts_with("LicenseRef-")
+                    || id.starts_with("DocumentRef-")
+                    || LICENSES.iter().any(|(license, _)| license == id)
+                {
+                    vec![]
+                } else {
+                    vec![id.clone()]
+                }
+            }
+            LicenseExpr::With { license, exception } => {
+                let mut ids = license.unknown_ids();
+                if !EXCEPTIONS.contains(&exception.as_str()) {
+                    ids.push(exception.clone());
+                }
+                ids
+            }
+            LicenseExpr::And(a, b) | LicenseExpr::Or(a, b) => {
+                let mut ids = a.unknown_ids();
+                ids.extend(b.unknown_ids());
+                ids
+            }
+        }
+    }
+}
+
+fn tokenize(expr: &str) -> Vec<String> {
+    expr.replace('(', " ( ")
+        .replace(')', " ) ")
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+struct Parser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<&str> {
+        let token = self.tokens.get(self.pos).map(String::as_str);
+        self.pos += 1;
+        token
+    }
+
+    fn next_is_operator(&self, operator: &str) -> bool {
+        self.peek()
+            .is_some_and(|token| token.eq_ignore_ascii_case(operator))
+    }
+
+    fn parse_or(&mut self) -> Result<LicenseExpr, String> {
+        let mut expr = self.parse_and()?;
+        while self.next_is_operator("OR") {
+            self.pos += 1;
+            expr = LicenseExpr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<LicenseExpr, String> {
+        let mut expr = self.parse_with()?;
+        while self.next_is_operator("AND") {
+            self.pos += 1;
+            expr = LicenseExpr::And(Box::new(expr), Box::new(self.parse_with()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_with(&mut self) -> Result<LicenseExpr, String> {
+        let license = self.parse_simple()?;
+        if !self.next_is_operator("WITH") {
+            return Ok(license);
+        }
+
+        self.pos += 1;
+        match self.next() {
+            Some(exception) if is_idstring(exception) => Ok(LicenseExpr::With {
+                license: Box::new(license),
+                exception: exception.to_string(),
+            }),
+            Some(token) => Err(format!("Expected an exception after WITH, found '{token}'")),
+            None => Err("Expected an exception after WITH".to_string()),
+        }
+    }
+
+    fn parse_simple(&mut self) -> Result<LicenseExpr, String> {
+        match self.next() {
+            Some("(") => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(")") => Ok(expr),
+                    _ => Err("Expected ')'".to_string()),
+                }
+            }
+            Some(token)
+                if ["AND", "OR", "WITH"]
+                    .iter()
+                    .any(|operator| token.eq_ignore_ascii_case(operator)) =>
+            {
+                Err(format!("Expected a license, found '{token}'"))
+            }
+            /* Flathub uses `LicenseRef-proprietary=https://...` to link to the license of proprietary apps. This isn't
+            valid SPDX, but it's treated as the `LicenseRef-` on its own. */
+            Some(token)
+                if token.split_once('=').is_some_and(|(license, url)| {
+                    license.starts_with("LicenseRef-") && is_idstring(license) && !url.is_empty()
+                }) =>
+            {
+                Ok(LicenseExpr::License {
+                    id: token.split_once('=').unwrap().0.to_string(),
+                    or_later: false,
+                })
+            }
+            Some(token) => {
+                let (id, or_later) = match token.strip_suffix('+') {
+                    Some(id) => (id, true),
+                    None => (token, false),
+                };
+
+                /* `DocumentRef-{doc}:LicenseRef-{license}` is the only place a colon is allowed */
+                let is_valid = match id.split_once(':') {
+                    Some((document, license)) => {
+                        document.starts_with("DocumentRef-")
+                            && license.starts_with("LicenseRef-")
+                            && is_idstring(document)
+                            && is_idstring(license)
+                    }
+                    None => is_idstring(id),
+                };
+
+                if !is_valid {
+                    return Err(format!("'{token}' is not a valid license identifier"));
+                }
+
+                /* Some identifiers on the list end with a `+` */
+                if LICENSES.iter().any(|(license, _)| *license == token) {
+                    Ok(LicenseExpr::License {
+                        id: token.to_string(),
+                        or_later: false,
+                    })
+                } else {
+                    Ok(LicenseExpr::License {
+                        id: id.to_string(),
+                        or_later,
+                    })
+                }
+            }
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+/// Whether a string only contains the characters allowed in SPDX identifiers (letters, digits, `.` and `-`).
+fn is_idstring(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn license(id: &str) -> Box<LicenseExpr> {
+        Box::new(LicenseExpr::License {
+            id: id.to_string(),
+            or_later: false,
+        })
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            LicenseExpr::parse("MIT OR Apache-2.0 AND LicenseRef-proprietary"),
+            Ok(LicenseExpr::Or(
+                license("MIT"),
+                Box::new(LicenseExpr::And(
+                    license("Apache-2.0"),
+                    license("LicenseRef-proprietary")
+                ))
+            ))
+        );
+        assert_eq!(
+            LicenseExpr::parse("(GPL-2.0+ with Classpath-exception-2.0)"),
+            Ok(LicenseExpr::With {
+                license: license("GPL-2.0+"),
+                exception: "Classpath-exception-2.0".to_string(),
+            })
+        );
+        assert_eq!(
+            LicenseExpr::parse("EPL-1.0+"),
+            Ok(LicenseExpr::License {
+                id: "EPL-1.0".to_string(),
+                or_later: true,
+            })
+        );
+
+        assert_eq!(
+            LicenseExpr::parse("LicenseRef-proprietary=https://example.com/eula.html"),
+            Ok(*license("LicenseRef-proprietary"))
+        );
+
+        assert!(LicenseExpr::parse("").is_err());
+        assert!(LicenseExpr::parse("MIT=https://example.com").is_err());
+        assert!(LicenseExpr::parse("LicenseRef-proprietary=").is_err());
+        assert!(LicenseExpr::parse("MIT OR").is_err());
+        assert!(LicenseExpr::parse("(MIT").is_err());
+        assert!(LicenseExpr::parse("MIT GPL-3.0-only").is_err());
+        assert!(LicenseExpr::parse("GPL v3").is_err());
+        assert!(LicenseExpr::parse("MIT, BSD-3-Clause").is_err());
+    }
+
+    #[test]
+    fn test_is_free_software() {
+        let is_free = |expr| LicenseExpr::parse(expr).unwrap().is_free_software();
+
+        assert!(is_free("GPL-3.0-or-later"));
+        assert!(is_free("MIT OR LicenseRef-proprietary"));
+        assert!(is_free("GPL-2.0-only WITH Classpath-exception-2.0"));
+        assert!(!is_free("LicenseRef-proprietary"));
+        assert!(!is_free(
+            "LicenseRef-proprietary=https://example.com/eula.html"
+        ));
+        assert!(!is_free("MIT AND LicenseRef-proprietary"));
+        assert!(!is_free("CC-BY-NC-SA-4.0"));
+        assert!(!is_free("Some-Unknown-License"));
+    }
+
+    #[test]
+    fn test_unknown_ids() {
+        let unknown_ids = |expr| LicenseExpr::parse(expr).unwrap().unknown_ids();
+
+        assert!(unknown_ids("MIT AND (LicenseRef-custom OR GPL-3.0+)").is_empty());
+        assert_eq!(
+            unknown_ids("GPL-3.0-only WITH Some-exception OR Foo-1.0"),
+            vec!["Some-exception", "Foo-1.0"]
+        );
+    }
+}
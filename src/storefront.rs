@@ -1,18 +1,39 @@
 use anyhow::{anyhow, Result};
 use log::info;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::utils::retry;
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(default)]
 pub struct StorefrontInfo {
     pub verification: Option<VerificationInfo>,
     pub pricing: Option<PricingInfo>,
     pub is_free_software: Option<bool>,
+    /// The app's end-of-life message. If the backend leaves this out, the commit keeps whatever it has (e.g. from the
+    /// `end-of-life` in the app's flathub.json); if it's `null`, the message is removed.
+    #[serde(
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub end_of_life: Option<Option<String>>,
+    /// The ID of the app that replaces this one, if it was renamed. Left out and `null` work like `end_of_life`.
+    #[serde(
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub end_of_life_rebase: Option<Option<String>>,
 }
 
-#[derive(Debug, Default, Deserialize)]
+/// Deserializes a value that is present, even if it's `null`, as `Some`. Together with `#[serde(default)]`, this
+/// distinguishes a missing field (`None`) from a `null` one (`Some(None)`).
+fn deserialize_some<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct VerificationInfo {
     pub verified: bool,
     pub timestamp: Option<String>,
@@ -23,7 +44,7 @@ pub struct VerificationInfo {
     pub login_is_organization: Option<bool>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PricingInfo {
     pub recommended_donation: Option<i32>,
     pub minimum_payment: Option<i32>,
@@ -0,0 +1,139 @@
+use std::{
+    io::{self, Read},
+    os::unix::process::CommandExt,
+    process::{Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+
+/// The most output we keep from each of a tool's stdout and stderr. Anything after this is discarded.
+pub const MAX_OUTPUT_LEN: usize = 1024 * 1024;
+
+pub enum ToolResult {
+    /// The tool exited on its own. Its stdout and stderr are truncated to `MAX_OUTPUT_LEN` bytes.
+    Exited(Output),
+    /// The tool didn't exit within the timeout, so it was killed.
+    TimedOut,
+}
+
+/// Runs an external tool and collects its output. If the tool doesn't exit within the timeout, it is killed along with
+/// any processes it started.
+pub fn run_tool(mut command: Command, timeout: Duration) -> Result<ToolResult> {
+    info!("Running {command:?}");
+
+    /* Put the tool in its own process group, so we can kill its children too (e.g. `flatpak run` starts bwrap, which
+    starts the actual tool) */
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+
+    /* Read the output in the background, otherwise the tool could block on a full pipe */
+    let stdout = read_truncated(child.stdout.take().unwrap());
+    let stderr = read_truncated(child.stderr.take().unwrap());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if Instant::now() >= deadline {
+            warn!(
+                "{:?} did not exit within {} seconds, killing it",
+                command.get_program(),
+                timeout.as_secs()
+            );
+
+            /* A negative PID means the whole process group */
+            if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+                child.kill()?;
+            }
+            child.wait()?;
+
+            /* Don't wait for the reader threads; if anything outside the process group still has the pipes open, they
+            might never finish */
+            return Ok(ToolResult::TimedOut);
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    Ok(ToolResult::Exited(Output {
+        status,
+        stdout: stdout
+            .join()
+            .map_err(|_| anyhow!("stdout reader panicked"))??,
+        stderr: stderr
+            .join()
+            .map_err(|_| anyhow!("stderr reader panicked"))??,
+    }))
+}
+
+fn read_truncated<R: Read + Send + 'static>(
+    mut pipe: R,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buffer = vec![];
+        pipe.by_ref()
+            .take(MAX_OUTPUT_LEN as u64)
+            .read_to_end(&mut buffer)?;
+
+        /* Keep draining the pipe so the tool doesn't block */
+        io::copy(&mut pipe, &mut io::sink())?;
+
+        Ok(buffer)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_tool() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo hello; echo world >&2; exit 3"]);
+
+        match run_tool(command, Duration::from_secs(10)).unwrap() {
+            ToolResult::Exited(output) => {
+                assert_eq!(output.status.code(), Some(3));
+                assert_eq!(output.stdout, b"hello\n");
+                assert_eq!(output.stderr, b"world\n");
+            }
+            ToolResult::TimedOut => panic!("tool timed out"),
+        }
+    }
+
+    #[test]
+    fn test_run_tool_truncates_output() {
+        let mut command = Command::new("head");
+        command.args(["-c", &(MAX_OUTPUT_LEN * 2).to_string(), "/dev/zero"]);
+
+        match run_tool(command, Duration::from_secs(10)).unwrap() {
+            ToolResult::Exited(output) => {
+                assert!(output.status.success());
+                assert_eq!(output.stdout.len(), MAX_OUTPUT_LEN);
+            }
+            ToolResult::TimedOut => panic!("tool timed out"),
+        }
+    }
+
+    #[test]
+    fn test_run_tool_timeout() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 30 & sleep 30"]);
+
+        let start = Instant::now();
+        assert!(matches!(
+            run_tool(command, Duration::from_secs(1)).unwrap(),
+            ToolResult::TimedOut
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -1,51 +1,195 @@
-use std::io::Read;
+use std::{io::Read, path::Path};
 
 use anyhow::{anyhow, Result};
 use elementtree::Element;
 use flate2::read::GzDecoder;
 use log::info;
 use ostree::{
-    gio::Cancellable,
+    gio::{Cancellable, File, FileQueryInfoFlags, FileType},
     glib,
-    glib::GString,
+    glib::{GString, KeyFile, KeyFileFlags, VariantDict},
     prelude::{Cast, FileExt, InputStreamExtManual},
     MutableTree, Repo, RepoFile,
 };
 
-pub const APP_SUFFIXES: [&str; 3] = ["Sources", "Debug", "Locale"];
-pub const APPID_SKIPLIST: [&str; 8] = [
-    "net.wz2100.wz2100",
-    "org.freedesktop.Platform.ClInfo",
-    "org.freedesktop.Platform.GlxInfo",
-    "org.freedesktop.Platform.VaInfo",
-    "org.freedesktop.Platform.VdpauInfo",
-    "org.freedesktop.Platform.VulkanInfo",
-    "org.mozilla.Thunderbird",
-    "org.mozilla.firefox",
-];
-
-pub fn app_id_from_ref(refstring: &str) -> String {
+/// The last components of the IDs of refs that go along with an app or runtime rather than being one, e.g.
+/// `org.gnome.Builder.Locale`. Used unless the config gives its own list.
+pub const DEFAULT_APP_SUFFIXES: [&str; 3] = ["Sources", "Debug", "Locale"];
+
+pub fn default_app_suffixes() -> Vec<String> {
+    DEFAULT_APP_SUFFIXES.map(str::to_string).to_vec()
+}
+
+/// Gets the ID of the app a ref belongs to, stripping any of `app_suffixes` (see `ValidateConfig::app_suffixes`), e.g.
+/// `org.gnome.Builder` for `runtime/org.gnome.Builder.Locale/x86_64/stable`.
+pub fn app_id_from_ref(refstring: &str, app_suffixes: &[String]) -> String {
     let ref_id = refstring.split('/').nth(1).unwrap().to_string();
     let id_parts: Vec<&str> = ref_id.split('.').collect();
 
-    if APP_SUFFIXES.contains(id_parts.last().unwrap()) {
+    if app_suffixes.iter().any(|s| s == id_parts.last().unwrap()) {
         id_parts[..id_parts.len() - 1].to_vec().join(".")
     } else {
         ref_id
     }
 }
 
-/// Determines whether the refstring is either an app or extension (as opposed to a Sources/Debug/Locales ref, or
-/// something else like the branch we store screenshots in).
-pub fn is_primary_ref(refstring: &str) -> bool {
-    if refstring.starts_with("app/") {
-        let appid = refstring.split('/').nth(1).unwrap().to_string();
-        !APPID_SKIPLIST.contains(&appid.as_str())
-    } else {
-        false
+/// Gets the ID component of a refstring, without stripping any suffixes, e.g. `org.gnome.Builder.Locale` for
+/// `runtime/org.gnome.Builder.Locale/x86_64/stable`.
+pub fn id_from_ref(refstring: &str) -> String {
+    refstring.split('/').nth(1).unwrap_or_default().to_string()
+}
+
+/// Gets the architecture component of a refstring, e.g. `x86_64` for `app/org.gnome.Builder/x86_64/stable`.
+pub fn arch_from_ref(refstring: &str) -> String {
+    refstring.split('/').nth(2).unwrap_or_default().to_string()
+}
+
+/// Checks whether a string is a valid Flatpak ID. Uses the same rules as flatpak's `flatpak_is_valid_name()`.
+pub fn is_valid_id(id: &str) -> bool {
+    let elements: Vec<&str> = id.split('.').collect();
+
+    id.len() <= 255
+        && elements.len() >= 2
+        && elements.iter().enumerate().all(|(i, element)| {
+            let is_last = i == elements.len() - 1;
+            element.chars().next().is_some_and(|c| !c.is_ascii_digit())
+                && element
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || (c == '-' && is_last))
+        })
+}
+
+/// Checks whether a string is a well-formed partial ref (`ID/ARCH/BRANCH`), as used for the runtime and SDK in an
+/// app's metadata.
+pub fn is_valid_partial_ref(partial_ref: &str) -> bool {
+    match partial_ref.split('/').collect::<Vec<_>>()[..] {
+        [id, arch, branch] => {
+            is_valid_id(id)
+                && !arch.is_empty()
+                && arch.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && !branch.is_empty()
+                && !branch.starts_with('-')
+                && branch
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+        }
+        _ => false,
     }
 }
 
+/// Matches a string against a glob pattern. `*` matches any number of characters (including `/`) and `?` matches
+/// exactly one character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    /* Iterative matching with backtracking to the last `*` */
+    let (mut p, mut t) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                last_star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match last_star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    last_star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The kinds of refs that can be uploaded in a build. Each kind gets its own set of validations.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RefKind {
+    App,
+    Runtime,
+    /// An extension of a runtime, e.g. a GL driver.
+    RuntimeExtension,
+    /// An extension of an app, e.g. a plugin.
+    AppExtension,
+    /// A Locale, Debug or Sources ref that goes along with an app or runtime.
+    Auxiliary,
+    /// The branch that mirrored screenshots are stored in.
+    Screenshots,
+    Unknown,
+}
+
+impl RefKind {
+    /// Classifies a ref. `extension_of` is the `ref` key of the `[ExtensionOf]` group in the ref's metadata, if there
+    /// is one, and refs whose IDs end in one of `app_suffixes` are auxiliary.
+    pub fn new(refstring: &str, extension_of: Option<&str>, app_suffixes: &[String]) -> Self {
+        if refstring.starts_with("screenshots/") {
+            return Self::Screenshots;
+        }
+
+        let ref_id = id_from_ref(refstring);
+        let last = ref_id.rsplit('.').next().unwrap_or_default();
+        if app_suffixes.iter().any(|s| s == last) {
+            return Self::Auxiliary;
+        }
+
+        if refstring.starts_with("app/") {
+            Self::App
+        } else if refstring.starts_with("runtime/") {
+            match extension_of {
+                Some(extension_of) if extension_of.starts_with("app/") => Self::AppExtension,
+                Some(_) => Self::RuntimeExtension,
+                None => Self::Runtime,
+            }
+        } else {
+            Self::Unknown
+        }
+    }
+
+    /// Classifies a ref in the given repo, reading its metadata to find out if it's an extension.
+    pub fn load(repo: &Repo, refstring: &str, checksum: &str, app_suffixes: &[String]) -> Self {
+        let extension_of = if refstring.starts_with("runtime/") {
+            load_metadata(repo, checksum)
+                .ok()
+                .and_then(|(_, metadata)| metadata.string("ExtensionOf", "ref").ok())
+        } else {
+            None
+        };
+
+        Self::new(refstring, extension_of.as_deref(), app_suffixes)
+    }
+
+    /// Whether this is an app, runtime, or extension, as opposed to a Sources/Debug/Locale ref or something else like
+    /// the branch we store screenshots in.
+    pub fn is_primary(self) -> bool {
+        matches!(
+            self,
+            Self::App | Self::Runtime | Self::RuntimeExtension | Self::AppExtension
+        )
+    }
+}
+
+/// Determines whether the refstring is either an app, runtime or extension (as opposed to a Sources/Debug/Locales ref,
+/// or something else like the branch we store screenshots in).
+pub fn is_primary_ref(refstring: &str, app_suffixes: &[String]) -> bool {
+    RefKind::new(refstring, None, app_suffixes).is_primary()
+}
+
+/// Opens the OSTree repo at the given path.
+pub fn open_repo(path: &Path) -> Result<Repo> {
+    let repo = Repo::new(&File::for_path(path));
+    repo.open(Cancellable::NONE)?;
+    Ok(repo)
+}
+
 pub fn mtree_lookup(
     mtree: &MutableTree,
     path: &[&str],
@@ -87,6 +231,79 @@ pub fn read_repo_file(file: &RepoFile) -> Result<Vec<u8>> {
     read_file_from_repo(&file.repo(), &file.checksum())
 }
 
+/// Loads the metadata dictionary of a commit (the `a{sv}` that holds keys like `xa.metadata` and `xa.subsets`).
+pub fn load_commit_metadata(repo: &Repo, checksum: &str) -> Result<VariantDict> {
+    let commit = repo.load_commit(checksum)?.0;
+    Ok(commit.child_get::<VariantDict>(0))
+}
+
+/// Reads up to `len` bytes from the start of a file. Useful for checking file headers without loading the whole file.
+pub fn read_file_header(file: &File, len: usize) -> Result<Vec<u8>> {
+    let mut buffer = vec![0; len];
+    let (read, _) = file
+        .read(Cancellable::NONE)?
+        .read_all(&mut buffer, Cancellable::NONE)?;
+    buffer.truncate(read);
+    Ok(buffer)
+}
+
+/// Recursively lists the regular files in a directory of a commit. Each file is returned with its path, which is the
+/// given path joined with the file's path relative to the directory. Symlinks are not followed. If the directory does
+/// not exist, an empty list is returned.
+pub fn list_files_recursive(dir: &File, path: &str) -> Result<Vec<(String, File)>> {
+    let mut files = vec![];
+
+    if dir.query_file_type(FileQueryInfoFlags::NOFOLLOW_SYMLINKS, Cancellable::NONE)
+        != FileType::Directory
+    {
+        return Ok(files);
+    }
+
+    for info in dir.enumerate_children(
+        "standard::name,standard::type",
+        FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+        Cancellable::NONE,
+    )? {
+        let info = info?;
+        let name = info.name();
+        let child = dir.child(&name);
+        let child_path = format!("{path}/{}", name.to_string_lossy());
+
+        match info.file_type() {
+            FileType::Directory => files.extend(list_files_recursive(&child, &child_path)?),
+            FileType::Regular => files.push((child_path, child)),
+            _ => {}
+        }
+    }
+
+    Ok(files)
+}
+
+/// Loads the `metadata` keyfile from the root of the given commit.
+pub fn load_metadata(repo: &Repo, checksum: &str) -> Result<(String, KeyFile)> {
+    let (file, _checksum) = repo.read_commit(checksum, Cancellable::NONE)?;
+
+    let metadata_file = file.resolve_relative_path("metadata");
+    let content = String::from_utf8(read_repo_file(metadata_file.downcast_ref().unwrap())?)?;
+
+    let keyfile = KeyFile::new();
+    keyfile.load_from_data(&content, KeyFileFlags::NONE)?;
+
+    Ok((content, keyfile))
+}
+
+/// Gets the path, relative to the commit root, of the app's `command` as declared in its metadata. Returns `None` if the
+/// command is an absolute path outside of `/app`.
+pub fn get_command_path(command: &str) -> Option<String> {
+    if let Some(path) = command.strip_prefix("/app/") {
+        Some(format!("files/{path}"))
+    } else if command.starts_with('/') {
+        None
+    } else {
+        Some(format!("files/bin/{command}"))
+    }
+}
+
 pub fn get_appstream_path(app_id: &str) -> String {
     format!("files/share/app-info/xmls/{app_id}.xml.gz")
 }
@@ -174,34 +391,180 @@ mod tests {
 
     #[test]
     fn test_app_id_from_refstring() {
+        let suffixes = default_app_suffixes();
+
         assert_eq!(
-            app_id_from_ref("app/org.gnome.Builder/x86_64/stable"),
+            app_id_from_ref("app/org.gnome.Builder/x86_64/stable", &suffixes),
             "org.gnome.Builder"
         );
         assert_eq!(
-            app_id_from_ref("runtime/org.gnome.Builder.Sources/x86_64/stable"),
+            app_id_from_ref("runtime/org.gnome.Builder.Sources/x86_64/stable", &suffixes),
             "org.gnome.Builder"
         );
         assert_eq!(
-            app_id_from_ref("runtime/org.gnome.Builder.Debug/x86_64/stable"),
+            app_id_from_ref("runtime/org.gnome.Builder.Debug/x86_64/stable", &suffixes),
             "org.gnome.Builder"
         );
         assert_eq!(
-            app_id_from_ref("runtime/org.gnome.Builder.Locale/x86_64/stable"),
+            app_id_from_ref("runtime/org.gnome.Builder.Locale/x86_64/stable", &suffixes),
             "org.gnome.Builder"
         );
         assert_eq!(
-            app_id_from_ref("runtime/org.gnome.Platform/x86_64/3.38"),
+            app_id_from_ref("runtime/org.gnome.Platform/x86_64/3.38", &suffixes),
             "org.gnome.Platform"
         );
+
+        /* The config can replace the list */
+        let suffixes = vec!["Docs".to_string()];
+        assert_eq!(
+            app_id_from_ref("runtime/org.gnome.Builder.Docs/x86_64/stable", &suffixes),
+            "org.gnome.Builder"
+        );
+        assert_eq!(
+            app_id_from_ref("runtime/org.gnome.Builder.Locale/x86_64/stable", &suffixes),
+            "org.gnome.Builder.Locale"
+        );
+    }
+
+    #[test]
+    fn test_arch_from_refstring() {
+        assert_eq!(
+            arch_from_ref("app/org.gnome.Builder/x86_64/stable"),
+            "x86_64"
+        );
+        assert_eq!(
+            arch_from_ref("runtime/org.gnome.Builder.Locale/aarch64/stable"),
+            "aarch64"
+        );
+        assert_eq!(arch_from_ref("screenshots/x86_64"), "x86_64");
+    }
+
+    #[test]
+    fn test_get_command_path() {
+        assert_eq!(get_command_path("main").as_deref(), Some("files/bin/main"));
+        assert_eq!(
+            get_command_path("/app/libexec/main").as_deref(),
+            Some("files/libexec/main")
+        );
+        assert_eq!(get_command_path("/usr/bin/main"), None);
+    }
+
+    #[test]
+    fn test_is_valid_id() {
+        assert!(is_valid_id("org.gnome.Builder"));
+        assert!(is_valid_id("org.freedesktop.Platform.GL.nvidia-535-104-05"));
+        assert!(is_valid_id("io.github._0x1.App"));
+        assert!(!is_valid_id("org"));
+        assert!(!is_valid_id("org..Builder"));
+        assert!(!is_valid_id("org.gnome.1Builder"));
+        assert!(!is_valid_id("org.my-project.Builder"));
+        assert!(!is_valid_id("org.gnome.Builder/x86_64"));
+    }
+
+    #[test]
+    fn test_is_valid_partial_ref() {
+        assert!(is_valid_partial_ref(
+            "org.freedesktop.Platform/x86_64/22.08"
+        ));
+        assert!(is_valid_partial_ref("org.gnome.Sdk/aarch64/master"));
+        assert!(!is_valid_partial_ref("org.freedesktop.Platform"));
+        assert!(!is_valid_partial_ref("org.freedesktop.Platform/x86_64"));
+        assert!(!is_valid_partial_ref("org.freedesktop.Platform/x86_64/"));
+        assert!(!is_valid_partial_ref("org.freedesktop.Platform//22.08"));
+        assert!(!is_valid_partial_ref(
+            "runtime/org.freedesktop.Platform/x86_64/22.08"
+        ));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(
+            "app/org.gnome.Builder/x86_64/stable",
+            "app/org.gnome.Builder/x86_64/stable"
+        ));
+        assert!(glob_match("app/*", "app/org.gnome.Builder/x86_64/stable"));
+        assert!(glob_match(
+            "*/org.gnome.Builder*/x86_64/*",
+            "runtime/org.gnome.Builder.Locale/x86_64/stable"
+        ));
+        assert!(glob_match(
+            "app/org.gnome.Builder/???????/*",
+            "app/org.gnome.Builder/aarch64/stable"
+        ));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match(
+            "app/*",
+            "runtime/org.gnome.Builder.Locale/x86_64/stable"
+        ));
+        assert!(!glob_match(
+            "*/x86_64/*",
+            "app/org.gnome.Builder/aarch64/stable"
+        ));
+        assert!(!glob_match("app/?", "app/"));
+    }
+
+    #[test]
+    fn test_ref_kind() {
+        let suffixes = default_app_suffixes();
+
+        assert_eq!(
+            RefKind::new("app/org.gnome.Builder/x86_64/stable", None, &suffixes),
+            RefKind::App
+        );
+        assert_eq!(
+            RefKind::new("runtime/org.gnome.Platform/x86_64/3.38", None, &suffixes),
+            RefKind::Runtime
+        );
+        assert_eq!(
+            RefKind::new(
+                "runtime/org.freedesktop.Platform.GL.nvidia-535-104-05/x86_64/1.4",
+                Some("runtime/org.freedesktop.Platform/x86_64/22.08"),
+                &suffixes
+            ),
+            RefKind::RuntimeExtension
+        );
+        assert_eq!(
+            RefKind::new(
+                "runtime/org.gnome.Builder.Plugin.Rust/x86_64/stable",
+                Some("app/org.gnome.Builder/x86_64/stable"),
+                &suffixes
+            ),
+            RefKind::AppExtension
+        );
+        assert_eq!(
+            RefKind::new(
+                "runtime/org.gnome.Builder.Locale/x86_64/stable",
+                Some("app/org.gnome.Builder/x86_64/stable"),
+                &suffixes
+            ),
+            RefKind::Auxiliary
+        );
+        assert_eq!(
+            RefKind::new("screenshots/x86_64", None, &suffixes),
+            RefKind::Screenshots
+        );
     }
 
     #[test]
     fn test_is_primary_ref() {
-        assert!(is_primary_ref("app/org.gnome.Builder/x86_64/stable"));
-        assert!(!is_primary_ref("runtime/org.gnome.Platform/x86_64/3.38"));
+        let suffixes = default_app_suffixes();
+
+        assert!(is_primary_ref(
+            "app/org.gnome.Builder/x86_64/stable",
+            &suffixes
+        ));
+        assert!(is_primary_ref(
+            "runtime/org.gnome.Platform/x86_64/3.38",
+            &suffixes
+        ));
         assert!(!is_primary_ref(
-            "runtime/org.gnome.Builder.Sources/x86_64/stable"
+            "runtime/org.gnome.Builder.Sources/x86_64/stable",
+            &suffixes
+        ));
+        assert!(!is_primary_ref("screenshots/x86_64", &suffixes));
+        assert!(is_primary_ref(
+            "runtime/org.gnome.Builder.Sources/x86_64/stable",
+            &[]
         ));
     }
 }
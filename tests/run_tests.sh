@@ -42,7 +42,8 @@ do
         cp -r refs/heads/app/com.example.WrongArchExecutable/x86_64 refs/heads/app/com.example.WrongArchExecutable/aarch64
     fi
 
-    cargo run -- validate > validation_result.json
+    # The linter's findings depend on its version, so only compare the built-in checks
+    cargo run -- validate --skip-external-linters > validation_result.json
     RESULT=$?
 
     cd -